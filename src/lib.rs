@@ -1,6 +1,14 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{self, BufRead, Read, Seek, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, Mutex};
+
+/// Called with each line of output, see [Build::on_output_line].
+type OutputCallback = Box<dyn Fn(&str) + Send + Sync>;
 
 /// A builder for configuring the build of LLVM.
 /// You need to call [Build::build] to actually build the LLVM source code.
@@ -10,15 +18,237 @@ pub struct Build {
     out_dir: Option<PathBuf>,
     profile: Option<String>,
     required_libs: Vec<String>,
+    projects: Vec<String>,
+    runtimes: Vec<String>,
+    z3: bool,
+    z3_dir: Option<PathBuf>,
+    reconfigure_only: bool,
+    extra_defines: Vec<(String, String)>,
+    configure_args: Vec<String>,
+    build_args: Vec<String>,
+    strip_prefix: Option<String>,
+    strip_suffix: Option<String>,
+    dump: Option<bool>,
+    component_shared: bool,
+    float_abi: Option<String>,
+    thin_archives: bool,
+    osx_deployment_target: Option<String>,
+    osx_architectures: Vec<String>,
+    compiler_launcher: Option<String>,
+    launcher_cache_dir: Option<PathBuf>,
+    strip: bool,
+    lto: LtoMode,
+    fat_lto_objects: bool,
+    docs: DocKind,
+    compile_commands_dest: Option<PathBuf>,
+    relative_rpath: bool,
+    append_vc_rev: bool,
+    include_dir: Option<PathBuf>,
+    curl: bool,
+    libedit: bool,
+    pic: bool,
+    link_group: Option<bool>,
+    whole_archive: bool,
+    generator: Option<String>,
+    toolset: Option<String>,
+    cache_mismatch: CacheMismatchPolicy,
+    c_compiler: Option<PathBuf>,
+    cxx_compiler: Option<PathBuf>,
+    host_c_compiler: Option<PathBuf>,
+    host_cxx_compiler: Option<PathBuf>,
+    native_toolchain_flags: Vec<String>,
+    unity_build: bool,
+    unity_batch_size: Option<usize>,
+    abi_breaking_checks: AbiBreakingChecks,
+    bytes_per_link_job: Option<usize>,
+    build_dylib: bool,
+    link_static: bool,
+    tools: Vec<String>,
+    expensive_checks: bool,
+    verify_artifacts: bool,
+    zlib: CompressionMode,
+    zstd: CompressionMode,
+    remarks: bool,
+    passes: bool,
+    include_paths: Vec<PathBuf>,
+    library_paths: Vec<PathBuf>,
+    mlir: bool,
+    verbose: bool,
+    diagnostics_color: Option<bool>,
+    tune_cpu: Option<String>,
+    install_components: Vec<String>,
+    reproducible: bool,
+    log_file: Option<PathBuf>,
+    on_output_line: Option<OutputCallback>,
+    scratch_dir: Option<PathBuf>,
+    clean_scratch_dir: bool,
 }
 
 /// The artifacts produced by the build.
 /// This is only created by calling [Build::build].
 /// You need to call [Artifacts::print_cargo_metadata] to print the cargo metadata.
 pub struct Artifacts {
-    include_dir: PathBuf,
-    lib_dir: PathBuf,
+    include_dirs: Vec<PathBuf>,
+    lib_dirs: Vec<PathBuf>,
     libs: Vec<String>,
+    link_kind: LinkKind,
+    runtime_libs: Vec<PathBuf>,
+    system_libs: Vec<String>,
+    static_system_libs: Vec<String>,
+    system_lib_dirs: Vec<PathBuf>,
+    docs_dir: Option<PathBuf>,
+    link_group: bool,
+    whole_archive: bool,
+    version: Option<String>,
+    prefix: PathBuf,
+    abi_breaking_checks: Option<bool>,
+    shared_lib: Option<String>,
+    link_shared_lib: bool,
+    binaries: Vec<PathBuf>,
+    clang_resource_dir: Option<PathBuf>,
+    lib_sizes: Vec<(String, u64)>,
+    cmake_cache: Option<PathBuf>,
+}
+
+/// The contents of a configuration file, see [Build::from_config_file].
+#[cfg(feature = "config")]
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BuildConfig {
+    host: Option<String>,
+    target: Option<String>,
+    out_dir: Option<PathBuf>,
+    profile: Option<String>,
+    required_libs: Option<Vec<String>>,
+    #[serde(default)]
+    projects: Vec<String>,
+    #[serde(default)]
+    runtimes: Vec<String>,
+    #[serde(default)]
+    defines: BTreeMap<String, String>,
+    #[serde(default)]
+    configure_args: Vec<String>,
+    #[serde(default)]
+    build_args: Vec<String>,
+}
+
+/// The cargo instructions planned for a build, see [Build::cargo_instructions].
+#[derive(Debug, Clone)]
+pub struct CargoInstructions {
+    link_search: Vec<PathBuf>,
+    link_libs: Vec<PlannedLib>,
+    include_dirs: Vec<PathBuf>,
+    lib_dirs: Vec<PathBuf>,
+}
+
+/// A library in [CargoInstructions::link_libs].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlannedLib {
+    /// A library known from the configuration alone.
+    Known { kind: LinkKind, name: String },
+    /// The libraries of an LLVM component and their system dependencies, only known after the build.
+    PendingBuild { kind: LinkKind, component: String },
+}
+
+/// How the LLVM libraries are linked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkKind {
+    /// Static archives, the default.
+    Static,
+    /// One shared library per component, see [Build::component_shared].
+    Dylib,
+}
+
+/// The link time optimization mode used to build LLVM, see [Build::lto].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LtoMode {
+    /// No link time optimization, the default.
+    Off,
+    /// ThinLTO, this requires clang.
+    Thin,
+    /// Full link time optimization.
+    Full,
+}
+
+/// The kind of documentation to build, see [Build::build_docs].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocKind {
+    /// No documentation, the default.
+    None,
+    /// Man pages of the LLVM tools, built with Sphinx.
+    Man,
+    /// HTML documentation, built with Sphinx.
+    Html,
+    /// HTML API reference, built with Doxygen.
+    Doxygen,
+}
+
+/// Whether LLVM is built with the checks that change the ABI of its C++ classes,
+/// see [Build::abi_breaking_checks].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbiBreakingChecks {
+    /// Enable the checks when assertions are enabled, the default.
+    WithAsserts,
+    /// Always enable the checks.
+    ForceOn,
+    /// Never enable the checks.
+    ForceOff,
+}
+
+/// A compression library LLVM can use, e.g. for compressed debug sections, see [Build::compression].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionBackend {
+    /// zlib, this maps to `LLVM_ENABLE_ZLIB`.
+    Zlib,
+    /// zstd, this maps to `LLVM_ENABLE_ZSTD`.
+    Zstd,
+}
+
+/// How a compression library is used, see [Build::compression].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionMode {
+    /// The library is not used, the default.
+    Off,
+    /// The system library is linked dynamically.
+    System,
+    /// The static library is linked into the build.
+    Bundled,
+}
+
+/// What to do when the cmake cache of a previous build has different values for the defines,
+/// see [Build::on_cache_mismatch].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMismatchPolicy {
+    /// Configure again with the existing cache, cmake may keep some of the old values.
+    Reconfigure,
+    /// Remove the cache and configure from scratch, the default.
+    Clean,
+    /// Fail with [BuildError::CacheMismatch].
+    Error,
+}
+
+/// An error that occurred while building LLVM, see [Build::try_build].
+#[derive(Debug)]
+pub enum BuildError {
+    /// A required parameter is not set, see [Build::new].
+    MissingParameter(&'static str),
+    /// The build directory was left in an inconsistent state by an interrupted build.
+    /// It must be removed before building again.
+    CorruptBuildDir { path: PathBuf },
+    /// The requested options can't be used together, or not with this target.
+    InvalidConfig(String),
+    /// An external command failed.
+    CommandFailed(String),
+    /// A tool required by the requested options is not installed.
+    MissingTool(&'static str),
+    /// The build produced no libraries that can be linked.
+    NoLibrariesFound { lib_dir: PathBuf },
+    /// A library is not a valid archive, see [Build::verify_artifacts].
+    CorruptArtifact { path: PathBuf },
+    /// The cmake cache of a previous build has a different value for a define, see [CacheMismatchPolicy::Error].
+    CacheMismatch { key: String, cached: String, requested: String },
+    /// An I/O error occurred.
+    Io(io::Error),
 }
 
 impl Build {
@@ -34,6 +264,48 @@ impl Build {
         Self::default()
     }
 
+    /// Create a `Build` from a TOML file, or a JSON file if the extension is `.json`,
+    /// so that the configuration can be shared between build scripts.
+    /// The file can set `host`, `target`, `out_dir`, `profile`, `required_libs`, `projects`, `runtimes`,
+    /// `defines`, `configure_args` and `build_args`, the other parameters are the same as [Build::new].
+    /// This requires the `config` feature.
+    #[cfg(feature = "config")]
+    pub fn from_config_file(path: &Path) -> Result<Build, BuildError> {
+        let contents = std::fs::read_to_string(path)?;
+        let is_json = path.extension().is_some_and(|ext| ext == "json");
+        let config: BuildConfig = if is_json {
+            serde_json::from_str(&contents).map_err(|e| e.to_string())
+        } else {
+            toml::from_str(&contents).map_err(|e| e.to_string())
+        }
+        .map_err(|e| BuildError::InvalidConfig(format!("{}: {}", path.display(), e)))?;
+
+        let mut build = Build::new();
+        if let Some(host) = &config.host {
+            build.host(host);
+        }
+        if let Some(target) = &config.target {
+            build.target(target);
+        }
+        if let Some(out_dir) = &config.out_dir {
+            build.out_dir(out_dir);
+        }
+        if let Some(profile) = &config.profile {
+            build.profile(profile);
+        }
+        if let Some(required_libs) = config.required_libs {
+            build.required_libs(required_libs);
+        }
+        build.projects = config.projects;
+        build.runtimes = config.runtimes;
+        for (key, value) in config.defines.iter() {
+            build.define(key, value);
+        }
+        build.configure_args = config.configure_args;
+        build.build_args = config.build_args;
+        Ok(build)
+    }
+
     /// Set the host triple.
     pub fn host(&mut self, host: &str) -> &mut Self {
         self.host = Some(host.to_string());
@@ -47,9 +319,12 @@ impl Build {
     }
 
     /// Set the output directory, this should be rust's "target" directory.
-    /// The actual build directory will be `$OUT_DIR/llvm-build`.
+    /// The actual build directory will be `$OUT_DIR/llvm-build`, `llvm-build` is not appended
+    /// again if `out_dir` already ends with it, so passing an existing build directory doesn't nest it.
+    /// The default is `$OUT_DIR/llvm-build`, from the `OUT_DIR` environment variable.
+    /// Relative paths are resolved against the current working directory when building.
     pub fn out_dir(&mut self, out_dir: &Path) -> &mut Self {
-        self.out_dir = Some(out_dir.join("llvm-build"));
+        self.out_dir = Some(llvm_build_dir(out_dir));
         self
     }
 
@@ -72,126 +347,3168 @@ impl Build {
         self
     }
 
-    /// Build the LLVM source code.
-    /// This will panic if any of the required environment variables are not set (see [Build::new]).
-    /// Returns an [Artifacts] struct, you will need to call [Artifacts::print_cargo_metadata]
-    /// to print the cargo metadata and configure the build in the build script.
-    pub fn build(&self) -> Artifacts {
-        let host = self.host.as_ref().expect("HOST not set").as_str();
-        let target = self.target.as_ref().expect("TARGET not set").as_str();
-        let profile = self.profile.as_ref().expect("PROFILE not set").as_str();
+    /// Set the LLVM projects to build, e.g. ["clang", "lld"].
+    /// These are passed to cmake as `LLVM_ENABLE_PROJECTS`.
+    /// The default is no additional projects.
+    pub fn enable_projects(&mut self, projects: &[&str]) -> &mut Self {
+        self.projects = projects.iter().map(|s| s.to_string()).collect();
+        self
+    }
 
-        let out_dir = self.out_dir.as_ref().expect("OUT_DIR not set");
-        let lib_dir = out_dir.join("lib");
-        let include_dir = out_dir.join("include");
+    /// Set the LLVM runtimes to build, e.g. ["compiler-rt", "libunwind"].
+    /// These are passed to cmake as `LLVM_ENABLE_RUNTIMES`, runtimes are not projects
+    /// and must not be passed to [Build::enable_projects].
+    /// Runtimes are compiled with the just-built clang, so "clang" must be enabled as a project.
+    /// The produced libraries are available through [Artifacts::runtime_libs].
+    pub fn enable_runtimes(&mut self, runtimes: &[&str]) -> &mut Self {
+        self.runtimes = runtimes.iter().map(|s| s.to_string()).collect();
+        self
+    }
 
-        let source_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("llvm-15.x/llvm");
+    /// Enable the Z3 constraint solver, used by the clang static analyzer.
+    /// Z3 must be installed, see [Build::z3_dir] to use a custom installation.
+    /// When enabled, the crate will link against the z3 dynamic library.
+    /// The default is `false`.
+    pub fn with_z3(&mut self, z3: bool) -> &mut Self {
+        self.z3 = z3;
+        self
+    }
 
-        let mut config = cmake::Config::new(source_dir);
+    /// Set the installation prefix of Z3, this is only used if [Build::with_z3] is enabled.
+    /// The z3 library is expected to be in `$Z3_DIR/lib`.
+    pub fn z3_dir(&mut self, z3_dir: &Path) -> &mut Self {
+        self.z3_dir = Some(z3_dir.to_path_buf());
+        self
+    }
 
-        config
-            .host(host)
-            .target(target)
-            .out_dir(out_dir)
-            .profile(profile)
-            .generator("Ninja")
-            .define("LLVM_PARALLEL_LINK_JOBS", "1")
-            .define("LLVM_ENABLE_LIBXML2", "OFF")
-            .define("LLVM_ENABLE_LIBCXX", "OFF")
-            .define("LLVM_ENABLE_PER_TARGET_RUNTIME_DIR", "OFF")
-            .define("LLVM_LINK_LLVM_DYLIB", "OFF")
-            .define("LLVM_ENABLE_PIC", "ON")
-            .define("LLVM_ENABLE_EH", "ON")
-            .define("LLVM_ENABLE_FFI", "ON")
-            .define("LLVM_ENABLE_RTTI", "ON")
-            .define("LLVM_INCLUDE_DOCS", "OFF")
-            .define("LLVM_INCLUDE_TESTS", "OFF")
-            .define("LLVM_INCLUDE_BENCHMARKS", "OFF")
-            .define("LLVM_INCLUDE_EXAMPLES", "OFF")
-            .define("LLVM_INSTALL_UTILS", "OFF")
-            .define("LLVM_ENABLE_BINDINGS", "OFF")
-            .define("LLVM_ENABLE_ZLIB", "OFF")
-            .define("LLVM_ENABLE_ZSTD", "OFF")
-            .define("LLVM_ENABLE_LIBPFM", "OFF")
-            .define("LLVM_ENABLE_Z3_SOLVER", "OFF")
-            .define("LLVM_OPTIMIZED_TABLEGEN", "ON")
-            .build();
+    /// Only run the cmake configure step, without building LLVM.
+    /// This is useful to refresh the cmake cache or `compile_commands.json`
+    /// (which is always generated in `$OUT_DIR/llvm-build/build`).
+    /// The returned [Artifacts] reflect what was already built, the list of libraries may be empty.
+    /// The default is `false`.
+    pub fn reconfigure_only(&mut self, reconfigure_only: bool) -> &mut Self {
+        self.reconfigure_only = reconfigure_only;
+        self
+    }
 
-        let mut libs = vec![];
+    /// Add a cmake define, passed as `-D<key>=<value>`.
+    /// Defines are applied after the ones set by this crate, so they override them.
+    pub fn define(&mut self, key: &str, value: &str) -> &mut Self {
+        self.extra_defines.push((key.to_string(), value.to_string()));
+        self
+    }
 
+    /// Append a raw argument to the cmake configure command line, e.g. "-Wno-dev".
+    /// Configure arguments are passed after all the defines, including the ones set with [Build::define].
+    pub fn configure_arg(&mut self, arg: &str) -> &mut Self {
+        self.configure_args.push(arg.to_string());
+        self
+    }
 
-        let llvm_config = out_dir.join("bin").join("llvm-config");
-        let libnames_output = Command::new(llvm_config)
-            .arg("--libnames")
-            .args(&self.required_libs)
-            .arg("--system-libs")
-            .arg("--link-static")
-            .output()
-            .expect("Failed to run llvm-config");
-
-        let libs_iter = String::from_utf8(libnames_output.stdout).expect("Failed to convert llvm-config output to string");
-        let libs_iter = libs_iter.split(&[' ','\n']).filter(|s| !s.is_empty());
-        for lib in libs_iter {
-            if let Some(lib_name) = lib.strip_prefix("lib").and_then(|s| s.strip_suffix(".a")) {
-                libs.push(lib_name.to_string());
-            } else if let Some(lib_name) = lib.strip_prefix("-l") {
-                libs.push(lib_name.to_string());
-            } else if let Some(lib_name) = lib.strip_suffix(".lib") {
-                libs.push(lib_name.to_string());
-            } else {
-                panic!("Unexpected library name: {}", lib);
-            }
-        }
+    /// Append a raw argument to the build command line.
+    /// Build arguments are passed to the native build tool (Ninja), after the `--` separator
+    /// that follows the arguments set by this crate.
+    pub fn build_arg(&mut self, arg: &str) -> &mut Self {
+        self.build_args.push(arg.to_string());
+        self
+    }
 
-        Artifacts {
-            include_dir,
-            lib_dir,
-            libs,
-        }
+    /// Set a custom prefix to strip from the library file names, as reported by `llvm-config`
+    /// or found in the lib directory, for LLVM forks or distribution builds that don't follow
+    /// the standard naming.
+    /// The remaining name is passed to `rustc-link-lib`.
+    /// When not set, the standard prefix of the target is stripped, `lib` except on Windows.
+    pub fn strip_prefix(&mut self, prefix: &str) -> &mut Self {
+        self.strip_prefix = Some(prefix.to_string());
+        self
     }
-}
 
-impl Default for Build {
-    fn default() -> Self {
-        let host = env::var("HOST").ok();
-        let target = env::var("TARGET").ok();
-        let out_dir = env::var_os("OUT_DIR").map(|s| PathBuf::from(s).join("llvm-build"));
-        let profile = env::var("PROFILE").ok();
-        let required_libs = vec!["all".to_string()];
+    /// Set a custom suffix to strip from the library file names, see [Build::strip_prefix].
+    /// When not set, the standard library extensions of the target are stripped.
+    pub fn strip_suffix(&mut self, suffix: &str) -> &mut Self {
+        self.strip_suffix = Some(suffix.to_string());
+        self
+    }
 
-        Self {
-            host,
-            target,
-            out_dir,
-            profile,
-            required_libs,
+    /// Keep the `dump()` debugging methods of LLVM objects, even in release builds.
+    /// This maps to `LLVM_ENABLE_DUMP`, it doesn't enable assertions.
+    /// When not set, the methods are available only if assertions are enabled.
+    pub fn enable_dump(&mut self, dump: bool) -> &mut Self {
+        self.dump = Some(dump);
+        self
+    }
+
+    /// Build one shared library per LLVM component, this maps to `BUILD_SHARED_LIBS`.
+    /// This is not the single `libLLVM` shared library, the two can't be enabled together.
+    /// The component libraries are linked dynamically, see [Artifacts::link_kind].
+    /// This is not supported on Windows.
+    /// The default is `false`.
+    pub fn component_shared(&mut self, component_shared: bool) -> &mut Self {
+        self.component_shared = component_shared;
+        self
+    }
+
+    /// Set the floating point ABI used to compile LLVM for ARM targets,
+    /// one of "soft", "softfp" or "hard", e.g. "hard" for `armv7-unknown-linux-gnueabihf`.
+    /// This is passed to the compiler as `-mfloat-abi`.
+    /// When not set, the compiler default is used.
+    pub fn float_abi(&mut self, abi: &str) -> &mut Self {
+        self.float_abi = Some(abi.to_string());
+        self
+    }
+
+    /// Build the static libraries as thin archives, which reference the object files
+    /// in the build directory instead of copying them, reducing the disk usage considerably.
+    /// Installed thin archives would point to missing objects, so [Artifacts::lib] is
+    /// `$OUT_DIR/llvm-build/build/lib` and the build directory must be kept.
+    /// This requires a GNU compatible `ar`, so it is not supported on MSVC and Apple targets.
+    /// The default is `false`.
+    pub fn thin_archives(&mut self, thin_archives: bool) -> &mut Self {
+        self.thin_archives = thin_archives;
+        self
+    }
+
+    /// Set the minimum macOS version the built libraries can run on, e.g. "11.0".
+    /// This maps to `CMAKE_OSX_DEPLOYMENT_TARGET`.
+    /// When not set, the version of the build machine is used.
+    pub fn osx_deployment_target(&mut self, version: &str) -> &mut Self {
+        self.osx_deployment_target = Some(version.to_string());
+        self
+    }
+
+    /// Set the macOS architectures to build for, e.g. ["arm64", "x86_64"] for universal libraries.
+    /// This maps to `CMAKE_OSX_ARCHITECTURES`.
+    /// When not set, the architecture of the target is used.
+    pub fn osx_architectures(&mut self, architectures: &[&str]) -> &mut Self {
+        self.osx_architectures = architectures.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    /// Set a compiler launcher, e.g. "ccache" or "sccache", to cache the compilation of LLVM.
+    /// This maps to `CMAKE_C_COMPILER_LAUNCHER` and `CMAKE_CXX_COMPILER_LAUNCHER`.
+    /// After the build, the launcher's cache statistics are printed as cargo warnings.
+    pub fn compiler_launcher(&mut self, launcher: &str) -> &mut Self {
+        self.compiler_launcher = Some(launcher.to_string());
+        self
+    }
+
+    /// Set the cache directory of the compiler launcher, e.g. to share it between CI jobs.
+    /// This sets both `CCACHE_DIR` and `SCCACHE_DIR` for the build, see [Build::compiler_launcher].
+    pub fn cache_dir_for_launcher(&mut self, dir: &Path) -> &mut Self {
+        self.launcher_cache_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    /// Strip the built libraries after the build, to reduce their size for distribution.
+    /// Debug information is removed from static archives, and unneeded symbols from shared
+    /// libraries, including libLLVM built with [Build::build_dylib].
+    /// `llvm-strip` is used if it's in `PATH`, otherwise the built one unless it was
+    /// cross-compiled, or the `strip` of the system.
+    /// This is not supported on MSVC targets and with [Build::thin_archives].
+    /// The default is `false`.
+    pub fn strip(&mut self, strip: bool) -> &mut Self {
+        self.strip = strip;
+        self
+    }
+
+    /// Set the link time optimization mode, this maps to `LLVM_ENABLE_LTO`.
+    /// The static libraries then contain LTO objects that only an LTO capable linker can read,
+    /// see [Build::fat_lto_objects].
+    /// The default is [LtoMode::Off].
+    pub fn lto(&mut self, lto: LtoMode) -> &mut Self {
+        self.lto = lto;
+        self
+    }
+
+    /// Emit regular object code next to the LTO data, so that the static libraries
+    /// can be linked without LTO (otherwise the linker fails with "file format not recognized").
+    /// The combinations with [Build::lto] are:
+    ///
+    /// - [LtoMode::Off]: no effect, objects are always regular.
+    /// - [LtoMode::Full]: passes `-ffat-lto-objects`, which is only honored by GCC.
+    /// - [LtoMode::Thin]: not supported, ThinLTO objects can't be fat.
+    ///
+    /// The default is `false`.
+    pub fn fat_lto_objects(&mut self, fat_lto_objects: bool) -> &mut Self {
+        self.fat_lto_objects = fat_lto_objects;
+        self
+    }
+
+    /// Build the LLVM documentation, the output directory is available through [Artifacts::docs_dir].
+    /// [DocKind::Man] and [DocKind::Html] require `sphinx-build`, [DocKind::Doxygen] requires `doxygen`.
+    /// The default is [DocKind::None].
+    pub fn build_docs(&mut self, docs: DocKind) -> &mut Self {
+        self.docs = docs;
+        self
+    }
+
+    /// Copy the `compile_commands.json` generated by cmake to `dest` after configuring,
+    /// e.g. to let clangd index C++ code using the LLVM headers.
+    /// Nothing is copied if the generator didn't produce the file.
+    pub fn emit_compile_commands(&mut self, dest: &Path) -> &mut Self {
+        self.compile_commands_dest = Some(dest.to_path_buf());
+        self
+    }
+
+    /// Set the RPATH of the shared libraries and tools to their own directory and to `../lib`,
+    /// `$ORIGIN;$ORIGIN/../lib` (`@loader_path` on Apple targets), instead of absolute build paths,
+    /// so they can be distributed next to the binary using them, and the tools in `bin` still
+    /// find the libraries in `lib`.
+    /// This has no effect on Windows, which has no RPATH.
+    /// The default is `false`.
+    pub fn relative_rpath(&mut self, relative_rpath: bool) -> &mut Self {
+        self.relative_rpath = relative_rpath;
+        self
+    }
+
+    /// Append the source control revision to the LLVM version string, this maps to `LLVM_APPEND_VC_REV`.
+    /// The default is `false`, so that `--version` is the same for every build of the same source.
+    pub fn append_vc_rev(&mut self, append_vc_rev: bool) -> &mut Self {
+        self.append_vc_rev = append_vc_rev;
+        self
+    }
+
+    /// Override the include directory reported in [Artifacts], e.g. for LLVM installs
+    /// harvested with [Build::from_build_dir] that don't keep the headers in `include`.
+    /// When not set, the `include` directory of the build is used.
+    pub fn include_dir(&mut self, dir: &Path) -> &mut Self {
+        self.include_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    /// Enable libcurl, used by the debuginfod client, this maps to `LLVM_ENABLE_CURL`.
+    /// When enabled, the crate will link against the curl dynamic library.
+    /// The default is `false`, so that the build doesn't depend on whether curl is installed.
+    pub fn with_curl(&mut self, curl: bool) -> &mut Self {
+        self.curl = curl;
+        self
+    }
+
+    /// Enable libedit, used for line editing in the interactive tools, this maps to `LLVM_ENABLE_LIBEDIT`.
+    /// When enabled, the crate will link against the libedit dynamic library.
+    /// The default is `false`, so that the build doesn't depend on whether libedit is installed.
+    pub fn with_libedit(&mut self, libedit: bool) -> &mut Self {
+        self.libedit = libedit;
+        self
+    }
+
+    /// Build position-independent code, this maps to `LLVM_ENABLE_PIC`.
+    /// This is required to link the static libraries into a `cdylib` or another shared library,
+    /// and for [Build::component_shared].
+    /// The default is `true`, it has no effect on windows.
+    pub fn pic(&mut self, pic: bool) -> &mut Self {
+        self.pic = pic;
+        self
+    }
+
+    /// Wrap the static LLVM libraries in `-Wl,--start-group` and `-Wl,--end-group` linker arguments,
+    /// so that the circular dependencies between them resolve regardless of their order.
+    /// Cargo only passes `rustc-link-arg` to the targets of the package printing them, not to its dependents.
+    /// This has no effect for MSVC and Apple targets, or with [Build::component_shared].
+    /// The default is `true` on other targets.
+    pub fn use_link_group(&mut self, link_group: bool) -> &mut Self {
+        self.link_group = Some(link_group);
+        self
+    }
+
+    /// Link every member of the static LLVM libraries, using the `+whole-archive` modifier of
+    /// `rustc-link-lib`.
+    /// This fixes targets or passes that are not registered at runtime, because the linker dropped
+    /// the archive members with their static initializers, but it makes binaries much larger.
+    /// The default is `false`.
+    pub fn whole_archive(&mut self, whole_archive: bool) -> &mut Self {
+        self.whole_archive = whole_archive;
+        self
+    }
+
+    /// Set the cmake generator, e.g. `Visual Studio 17 2022`.
+    /// The default is `Ninja`.
+    pub fn generator(&mut self, generator: &str) -> &mut Self {
+        self.generator = Some(generator.to_string());
+        self
+    }
+
+    /// Set the generator toolset, this maps to the `-T` argument of cmake, e.g. `v142` or `host=x64`.
+    /// This is only supported by the Visual Studio generators, see [Build::generator].
+    /// The default is the toolset chosen by cmake.
+    pub fn toolset(&mut self, toolset: &str) -> &mut Self {
+        self.toolset = Some(toolset.to_string());
+        self
+    }
+
+    /// Set what to do when reusing a build directory whose cmake cache has different values for the defines.
+    /// The default is [CacheMismatchPolicy::Clean], so that a stale cache never overrides the configuration.
+    pub fn on_cache_mismatch(&mut self, policy: CacheMismatchPolicy) -> &mut Self {
+        self.cache_mismatch = policy;
+        self
+    }
+
+    /// Set the C compiler for the target, this maps to `CMAKE_C_COMPILER`.
+    /// The default is chosen by the `cmake` crate, e.g. from the `CC` environment variable.
+    pub fn c_compiler(&mut self, path: &Path) -> &mut Self {
+        self.c_compiler = Some(path.to_path_buf());
+        self
+    }
+
+    /// Set the C++ compiler for the target, this maps to `CMAKE_CXX_COMPILER`.
+    /// The default is chosen by the `cmake` crate, e.g. from the `CXX` environment variable.
+    pub fn cxx_compiler(&mut self, path: &Path) -> &mut Self {
+        self.cxx_compiler = Some(path.to_path_buf());
+        self
+    }
+
+    /// Set the C compiler for the native tools built when cross-compiling, e.g. `llvm-tblgen`.
+    /// This is passed to the native build through `CROSS_TOOLCHAIN_FLAGS_NATIVE`.
+    /// The default is chosen by cmake for the native build.
+    pub fn host_c_compiler(&mut self, path: &Path) -> &mut Self {
+        self.host_c_compiler = Some(path.to_path_buf());
+        self
+    }
+
+    /// Set the C++ compiler for the native tools built when cross-compiling, see [Build::host_c_compiler].
+    /// The default is chosen by cmake for the native build.
+    pub fn host_cxx_compiler(&mut self, path: &Path) -> &mut Self {
+        self.host_cxx_compiler = Some(path.to_path_buf());
+        self
+    }
+
+    /// Add a cmake argument for the native tools built when cross-compiling, e.g. `-DCMAKE_AR=/usr/bin/ar`,
+    /// this is appended to `CROSS_TOOLCHAIN_FLAGS_NATIVE` after the [Build::host_c_compiler] and
+    /// [Build::host_cxx_compiler] ones.
+    /// The flags of the target build are not passed to the native build.
+    /// This can be called multiple times.
+    pub fn native_toolchain_flag(&mut self, flag: &str) -> &mut Self {
+        self.native_toolchain_flags.push(flag.to_string());
+        self
+    }
+
+    /// Compile the sources of each library in batches, this maps to `CMAKE_UNITY_BUILD`.
+    /// This is faster, but each compiler process needs a lot more memory, so it can run out of memory
+    /// on machines with many cores and little RAM.
+    /// The default is `false`.
+    pub fn unity_build(&mut self, unity_build: bool) -> &mut Self {
+        self.unity_build = unity_build;
+        self
+    }
+
+    /// Set the number of sources compiled together with [Build::unity_build],
+    /// this maps to `CMAKE_UNITY_BUILD_BATCH_SIZE`, larger batches need more memory.
+    /// The default is the cmake default, 8 sources.
+    pub fn unity_batch_size(&mut self, size: usize) -> &mut Self {
+        self.unity_batch_size = Some(size);
+        self
+    }
+
+    /// Set when the ABI breaking checks are enabled, this maps to `LLVM_ABI_BREAKING_CHECKS`.
+    /// C++ code linked against LLVM must be compiled with the same setting,
+    /// the result is available through [Artifacts::abi_breaking_checks].
+    /// The default is [AbiBreakingChecks::WithAsserts].
+    pub fn abi_breaking_checks(&mut self, mode: AbiBreakingChecks) -> &mut Self {
+        self.abi_breaking_checks = mode;
+        self
+    }
+
+    /// Run as many link jobs in parallel as fit in the memory of the build machine,
+    /// assuming each needs `bytes_per_job`, this maps to `LLVM_PARALLEL_LINK_JOBS`.
+    /// There are never more link jobs than CPUs, and only one if the memory can't be determined.
+    /// The number of jobs is computed when configuring, so it doesn't change [Build::config_hash].
+    /// The default is a single link job.
+    pub fn auto_link_jobs(&mut self, bytes_per_job: usize) -> &mut Self {
+        self.bytes_per_link_job = Some(bytes_per_job);
+        self
+    }
+
+    /// Also build the libLLVM shared library, this maps to `LLVM_BUILD_LLVM_DYLIB`.
+    /// The static libraries are still built, both are available through [Artifacts::static_libs]
+    /// and [Artifacts::shared_lib].
+    /// This is not supported on MSVC targets, or with [Build::component_shared].
+    /// The default is `false`.
+    pub fn build_dylib(&mut self, build_dylib: bool) -> &mut Self {
+        self.build_dylib = build_dylib;
+        self
+    }
+
+    /// Link the static libraries, or the libLLVM shared library built with [Build::build_dylib],
+    /// in [Artifacts::print_cargo_metadata].
+    /// The default is `true`.
+    pub fn link_static(&mut self, link_static: bool) -> &mut Self {
+        self.link_static = link_static;
+        self
+    }
+
+    /// Only build and install the given tools and their dependencies, e.g. ["clang", "opt", "llc"],
+    /// this maps to `LLVM_DISTRIBUTION_COMPONENTS`.
+    /// No libraries are linked, the paths of the tools are available through [Artifacts::binaries].
+    /// The default is to build the libraries.
+    pub fn tools_only(&mut self, tools: &[&str]) -> &mut Self {
+        self.tools = tools.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    /// Enable the expensive internal consistency checks, e.g. of the machine code verifier,
+    /// this maps to `LLVM_ENABLE_EXPENSIVE_CHECKS` and also enables assertions.
+    /// LLVM becomes many times slower, and on linux it's built with `_GLIBCXX_DEBUG`,
+    /// which changes the ABI of the C++ standard library types.
+    /// The default is `false`.
+    pub fn expensive_checks(&mut self, expensive_checks: bool) -> &mut Self {
+        self.expensive_checks = expensive_checks;
+        self
+    }
+
+    /// Check the headers of the static libraries after the build, and fail with
+    /// [BuildError::CorruptArtifact] if one is truncated, e.g. in a damaged CI cache.
+    /// This reads the member headers of each archive, not their contents.
+    /// The default is `false`.
+    pub fn verify_artifacts(&mut self, verify: bool) -> &mut Self {
+        self.verify_artifacts = verify;
+        self
+    }
+
+    /// Set how a compression library is used.
+    /// When it's not [CompressionMode::Off] the library is required, the build fails if it's not found.
+    /// [CompressionMode::Bundled] libraries are linked statically, so they must be in the linker search path.
+    /// The default is [CompressionMode::Off] for every backend.
+    pub fn compression(&mut self, backend: CompressionBackend, mode: CompressionMode) -> &mut Self {
+        match backend {
+            CompressionBackend::Zlib => self.zlib = mode,
+            CompressionBackend::Zstd => self.zstd = mode,
         }
+        self
     }
-}
 
-impl Artifacts {
-    /// Get the include directory.
-    pub fn include(&self) -> &Path {
-        &self.include_dir
+    /// Build MLIR, this adds "mlir" to [Build::enable_projects].
+    /// The MLIR libraries come before the LLVM ones in [Artifacts::libs], ordered by their dependencies,
+    /// and the headers generated by MLIR's tablegen are found in the include directories.
+    /// The default is `false`.
+    pub fn mlir(&mut self, mlir: bool) -> &mut Self {
+        self.mlir = mlir;
+        self
     }
 
-    /// Get the lib directory.
-    pub fn lib(&self) -> &Path {
-        &self.lib_dir
+    /// Print a summary of the build as cargo warnings, e.g. the total size of the libraries.
+    /// The default is `false`.
+    pub fn verbose(&mut self, verbose: bool) -> &mut Self {
+        self.verbose = verbose;
+        self
     }
 
-    /// Get the list of libraries.
-    pub fn libs(&self) -> &[String] {
-        &self.libs
+    /// Force colored compiler diagnostics on or off, e.g. to keep them colored in CI logs,
+    /// with `-fdiagnostics-color` and `CMAKE_COLOR_DIAGNOSTICS`.
+    /// This has no effect with MSVC.
+    /// The default is `None`, the compiler decides from the terminal.
+    pub fn diagnostics_color(&mut self, color: Option<bool>) -> &mut Self {
+        self.diagnostics_color = color;
+        self
     }
 
-    /// Print the cargo metadata.
-    pub fn print_cargo_metadata(&self) {
-        println!("cargo:rustc-link-search=native={}", self.lib_dir.display());
-        for lib in self.libs.iter() {
-            println!("cargo:rustc-link-lib=static={}", lib);
+    /// Optimize the code of LLVM itself for a CPU, e.g. "x86-64-v3" or "apple-m1",
+    /// with `-march` on x86 targets and `-mcpu` on the others.
+    /// The built libraries and tools may not run on older CPUs.
+    /// This doesn't change the targets LLVM can generate code for, and it's not supported with MSVC.
+    /// The default is the baseline CPU of the target.
+    pub fn tune_cpu(&mut self, cpu: &str) -> &mut Self {
+        self.tune_cpu = Some(cpu.to_string());
+        self
+    }
+
+    /// Only install the given cmake components in [Build::build_and_install],
+    /// e.g. ["llvm-headers", "LLVMCore", "llvm-config"].
+    /// The default is to install everything, or only the [Build::tools_only] tools.
+    pub fn install_components(&mut self, components: &[&str]) -> &mut Self {
+        self.install_components = components.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    /// Remove the sources of nondeterminism from the build, so that different machines
+    /// building the same configuration produce the same files. This:
+    ///
+    /// - sets `LLVM_APPEND_VC_REV` to `OFF`, overriding [Build::append_vc_rev]
+    /// - sets `LLVM_ENABLE_TERMINFO` to `OFF`, the last dependency detected automatically
+    /// - creates archives in deterministic mode (`ar D`) and sets `ZERO_AR_DATE` for Apple's `ar`
+    /// - maps the source directory to `llvm-src` and the build directory to `llvm-build`
+    ///   in the debug info and in `__FILE__` with `-ffile-prefix-map`
+    ///
+    /// The libraries are always discovered in a deterministic order.
+    /// The default is `false`.
+    pub fn reproducible(&mut self, reproducible: bool) -> &mut Self {
+        self.reproducible = reproducible;
+        self
+    }
+
+    /// Write the output of the build step, e.g. the Ninja output, to a file.
+    /// The output is still printed, so it's shown by cargo when the build fails.
+    /// The output of the configure step is not captured, cmake writes it to
+    /// `CMakeFiles/CMakeConfigureLog.yaml`, or `CMakeFiles/CMakeOutput.log` before CMake 3.26.
+    pub fn log_file(&mut self, path: &Path) -> &mut Self {
+        self.log_file = Some(path.to_path_buf());
+        self
+    }
+
+    /// Call `callback` with each line of output of the build step, see [Build::log_file].
+    pub fn on_output_line(&mut self, callback: impl Fn(&str) + Send + Sync + 'static) -> &mut Self {
+        self.on_output_line = Some(Box::new(callback));
+        self
+    }
+
+    /// Link the optimization remarks library, `LLVMRemarks`, with its YAML and bitstream serializers,
+    /// even if it's not a dependency of the [Build::required_libs].
+    /// The default is `false`.
+    pub fn with_remarks(&mut self, remarks: bool) -> &mut Self {
+        self.remarks = remarks;
+        self
+    }
+
+    /// Link the pass infrastructure libraries, `LLVMPasses` and `LLVMipo`, e.g. for pass plugins,
+    /// even if they're not dependencies of the [Build::required_libs].
+    /// The default is `false`.
+    pub fn with_passes(&mut self, passes: bool) -> &mut Self {
+        self.passes = passes;
+        self
+    }
+
+    /// Add a directory where cmake searches for the headers of dependencies, e.g. Z3 or zlib,
+    /// this is appended to `CMAKE_INCLUDE_PATH`.
+    /// This can be called multiple times.
+    pub fn include_path(&mut self, dir: &Path) -> &mut Self {
+        self.include_paths.push(dir.to_path_buf());
+        self
+    }
+
+    /// Add a directory where cmake searches for the libraries of dependencies,
+    /// this is appended to `CMAKE_LIBRARY_PATH`.
+    /// This can be called multiple times.
+    pub fn library_path(&mut self, dir: &Path) -> &mut Self {
+        self.library_paths.push(dir.to_path_buf());
+        self
+    }
+
+    /// Set a separate directory for the transient cmake build tree, e.g. on a ramdisk.
+    /// LLVM is built in `$SCRATCH_DIR/build` and then installed into the output directory,
+    /// which is what [Artifacts] reference.
+    /// When not set, the build tree is `$OUT_DIR/llvm-build/build`.
+    pub fn scratch_dir(&mut self, dir: &Path) -> &mut Self {
+        self.scratch_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    /// Remove the build tree from the scratch directory after a successful build, see [Build::scratch_dir].
+    /// The next build will start from scratch.
+    /// This can't be used with [Build::thin_archives], which reference the build tree.
+    /// The default is `false`.
+    pub fn clean_scratch_dir(&mut self, clean: bool) -> &mut Self {
+        self.clean_scratch_dir = clean;
+        self
+    }
+
+    /// Build the LLVM source code.
+    /// This will panic if any of the required environment variables are not set (see [Build::new]),
+    /// or if the build fails, see [Build::try_build].
+    /// Returns an [Artifacts] struct, you will need to call [Artifacts::print_cargo_metadata]
+    /// to print the cargo metadata and configure the build in the build script.
+    pub fn build(&self) -> Artifacts {
+        self.try_build().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Build the LLVM source code, like [Build::build], but return an error instead of panicking.
+    /// When cmake fails, the end of its logs are printed as cargo warnings and
+    /// [BuildError::CommandFailed] is returned.
+    pub fn try_build(&self) -> Result<Artifacts, BuildError> {
+        let out_dir = self.cmake_build(true)?;
+        let target = self.target.as_deref().ok_or(BuildError::MissingParameter("TARGET"))?;
+        self.discover(&out_dir, self.lib_dir(&out_dir), target)
+    }
+
+    /// Configure and build LLVM with cmake, and return the absolute output directory.
+    /// LLVM is installed in the output directory when `install` is set, otherwise it's only built
+    /// in the build tree, so that [Build::build_and_install] installs it only once.
+    fn cmake_build(&self, install: bool) -> Result<PathBuf, BuildError> {
+        let host = self.host.as_deref().ok_or(BuildError::MissingParameter("HOST"))?;
+        let target = self.target.as_deref().ok_or(BuildError::MissingParameter("TARGET"))?;
+        let profile = self.profile.as_deref().ok_or(BuildError::MissingParameter("PROFILE"))?;
+
+        // cmake runs from a different working directory, relative paths must be resolved here.
+        let out_dir = self.out_dir.as_ref().ok_or(BuildError::MissingParameter("OUT_DIR"))?;
+        let out_dir = std::path::absolute(out_dir)?;
+
+        self.validate(target)?;
+
+        // A build interrupted while configuring leaves a cache without the generated build files,
+        // cmake can fail in confusing ways when reusing it.
+        let build_dir = self.build_dir(&out_dir);
+        let generator = self.generator.as_deref().unwrap_or("Ninja");
+        let build_file = if generator.starts_with("Ninja") {
+            "build.ninja"
+        } else if generator.starts_with("Visual Studio") {
+            "LLVM.sln"
+        } else if generator == "Xcode" {
+            "LLVM.xcodeproj"
+        } else {
+            "Makefile"
+        };
+        if build_dir.join("CMakeCache.txt").exists() && !build_dir.join(build_file).exists() {
+            return Err(BuildError::CorruptBuildDir { path: build_dir });
+        }
+        let in_progress = out_dir.join(IN_PROGRESS_FILE);
+        if in_progress.exists() {
+            println!("cargo:warning=Resuming an interrupted LLVM build in {}", out_dir.display());
+        }
+
+        let stamp = out_dir.join(STAMP_FILE);
+        let config_hash = self.config_hash();
+        let tree = if install { &out_dir } else { &build_dir };
+        let llvm_config = tree
+            .join("bin")
+            .join(format!("llvm-config{}", env::consts::EXE_SUFFIX));
+        let built = if self.tools.is_empty() {
+            llvm_config.exists()
+        } else {
+            self.binaries(tree, target).iter().all(|path| path.exists())
+        };
+        if !self.reconfigure_only
+            && built
+            && std::fs::read_to_string(&stamp).is_ok_and(|s| s.trim() == config_hash)
+        {
+            // Nothing changed since the last successful build.
+            self.copy_compile_commands(&build_dir)?;
+            return Ok(out_dir);
+        }
+
+        let cache = build_dir.join("CMakeCache.txt");
+        if self.cache_mismatch != CacheMismatchPolicy::Reconfigure && cache.exists() {
+            let cached = read_cmake_cache(&cache)?;
+            let mismatch = self
+                .defines()
+                .into_iter()
+                .filter(|(key, _)| !NORMALIZED_DEFINES.contains(&key.as_str()))
+                .find(|(key, value)| cached.get(key).is_some_and(|cached| cached != value));
+            if let Some((key, requested)) = mismatch {
+                if self.cache_mismatch == CacheMismatchPolicy::Error {
+                    return Err(BuildError::CacheMismatch {
+                        cached: cached[&key].clone(),
+                        key,
+                        requested,
+                    });
+                }
+                println!(
+                    "cargo:warning=The cmake cache has a different value for {}, configuring from scratch",
+                    key
+                );
+                std::fs::remove_file(&cache)?;
+                // The results of the compiler checks are kept here.
+                let cmake_files = build_dir.join("CMakeFiles");
+                if cmake_files.exists() {
+                    std::fs::remove_dir_all(cmake_files)?;
+                }
+            }
+        }
+
+        let mut config = cmake::Config::new(source_dir());
+
+        config
+            .host(host)
+            .target(target)
+            .out_dir(build_dir.parent().unwrap_or(&out_dir))
+            .profile(profile)
+            .generator(generator);
+        if let Some(toolset) = &self.toolset {
+            config.generator_toolset(toolset);
+        }
+        if self.scratch_dir.is_some() {
+            config.define("CMAKE_INSTALL_PREFIX", &out_dir);
+        }
+
+        // The link jobs depend on the build machine, so they are not part of the defines.
+        config.define("LLVM_PARALLEL_LINK_JOBS", self.link_jobs().to_string());
+        for (key, value) in self.defines() {
+            config.define(key, value);
+        }
+        for flag in self.compiler_flags() {
+            config.cflag(&flag).cxxflag(&flag);
+        }
+        let vars = self.build_env(host, target);
+        for (key, value) in vars.iter() {
+            config.env(key, value);
+        }
+        for arg in self.configure_args.iter() {
+            config.configure_arg(arg);
+        }
+        // The cmake crate inherits the output of cmake, so the build step runs separately to capture it.
+        let multi_config_ide = generator.starts_with("Visual Studio") || generator == "Xcode";
+        let build_target = match (install, self.tools.is_empty()) {
+            (true, true) => "install",
+            (true, false) => "install-distribution",
+            (false, true) if multi_config_ide => "ALL_BUILD",
+            (false, true) => "all",
+            (false, false) => "distribution",
+        };
+        // The configure step always runs, these targets don't build anything.
+        if generator.starts_with("Visual Studio") {
+            config.build_target("ZERO_CHECK");
+        } else {
+            config.build_target("help");
+        }
+
+        // An interrupted build must not be mistaken for a finished one.
+        let _ = std::fs::remove_file(&stamp);
+        std::fs::create_dir_all(&out_dir)?;
+        std::fs::write(&in_progress, "")?;
+        let mut run = || {
+            // The cmake crate panics when a command fails.
+            let built = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| config.build()));
+            if let Err(panic) = built {
+                print_log_tails(&build_dir);
+                let message = panic
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_default();
+                return Err(BuildError::CommandFailed(format!("cmake: {}", message.trim())));
+            }
+            if !self.reconfigure_only {
+                self.run_build_step(&build_dir, profile, build_target, &vars)?;
+            }
+            Ok(())
+        };
+        // A failed build finished, it must not be reported as interrupted by the next one.
+        if let Err(e) = run() {
+            std::fs::remove_file(&in_progress)?;
+            return Err(e);
+        }
+
+        self.copy_compile_commands(&build_dir)?;
+        if self.strip && install && !self.reconfigure_only {
+            self.strip_libs(&out_dir, &self.lib_dir(&out_dir), target)?;
+        }
+        if !self.reconfigure_only {
+            std::fs::write(out_dir.join(DEFINES_FILE), defines_json(&self.defines()))?;
+            std::fs::write(&stamp, &config_hash)?;
+        }
+        std::fs::remove_file(&in_progress)?;
+        if self.clean_scratch_dir && self.scratch_dir.is_some() && !self.reconfigure_only {
+            std::fs::remove_dir_all(&build_dir)?;
+        }
+
+        if let Some(launcher) = &self.compiler_launcher {
+            self.print_launcher_stats(launcher);
+        }
+        Ok(out_dir)
+    }
+
+    /// Build the LLVM source code like [Build::try_build], but without installing it in
+    /// `$OUT_DIR/llvm-build`, then install it with `cmake --install` into a clean
+    /// `$OUT_DIR/llvm-build/install` and get the artifacts of the installed tree.
+    /// The installed tree doesn't depend on the build directory, so it can be moved or archived.
+    /// This can't be used with [Build::clean_scratch_dir], which removes the build tree before
+    /// the install, or with [Build::thin_archives], whose copies would point to missing object files.
+    /// Only some components can be installed with [Build::install_components].
+    pub fn build_and_install(&self) -> Result<Artifacts, BuildError> {
+        if self.clean_scratch_dir && self.scratch_dir.is_some() {
+            return Err(BuildError::InvalidConfig(
+                "build_and_install can't be used with clean_scratch_dir".to_string(),
+            ));
+        }
+        if self.thin_archives {
+            return Err(BuildError::InvalidConfig(
+                "build_and_install can't be used with thin archives".to_string(),
+            ));
+        }
+        let out_dir = self.cmake_build(false)?;
+        let host = self.host.as_deref().ok_or(BuildError::MissingParameter("HOST"))?;
+        let target = self.target.as_deref().ok_or(BuildError::MissingParameter("TARGET"))?;
+        let profile = self.profile.as_deref().ok_or(BuildError::MissingParameter("PROFILE"))?;
+        if self.reconfigure_only {
+            return self.discover(&out_dir, self.lib_dir(&out_dir), target);
+        }
+
+        let prefix = out_dir.join("install");
+        if prefix.exists() {
+            std::fs::remove_dir_all(&prefix)?;
+        }
+
+        // Tool-only builds don't build everything, so only their components can be installed.
+        let cmake = env::var_os("CMAKE").unwrap_or_else(|| "cmake".into());
+        let vars = self.build_env(host, target);
+        let components = if !self.install_components.is_empty() {
+            self.install_components.iter().map(Some).collect()
+        } else if !self.tools.is_empty() {
+            self.tools.iter().map(Some).collect()
+        } else {
+            vec![None]
+        };
+        for component in components {
+            let mut command = Command::new(&cmake);
+            command
+                .envs(vars.iter().map(|(key, value)| (key, value)))
+                .arg("--install")
+                .arg(self.build_dir(&out_dir))
+                .arg("--prefix")
+                .arg(&prefix)
+                .arg("--config")
+                .arg(profile);
+            if let Some(component) = component {
+                command.arg("--component").arg(component);
+            }
+            let status = command.status()?;
+            if !status.success() {
+                return Err(BuildError::CommandFailed(format!(
+                    "cmake --install {} exited with {}",
+                    prefix.display(),
+                    status
+                )));
+            }
+        }
+
+        if self.strip {
+            self.strip_libs(&prefix, &prefix.join("lib"), target)?;
+        }
+        let mut artifacts = self.discover(&prefix, prefix.join("lib"), target)?;
+        artifacts.cmake_cache = Some(self.build_dir(&out_dir).join("CMakeCache.txt"));
+        Ok(artifacts)
+    }
+
+    /// Get the environment of the cmake commands: the one of the C compiler, which the cmake crate
+    /// also sets, e.g. `INCLUDE` and `LIB` for MSVC, and the one required by the options.
+    fn build_env(&self, host: &str, target: &str) -> Vec<(OsString, OsString)> {
+        let mut vars: Vec<_> = cc::Build::new()
+            .cargo_metadata(false)
+            .opt_level(0)
+            .debug(false)
+            .warnings(false)
+            .host(host)
+            .target(target)
+            .try_get_compiler()
+            .map(|compiler| {
+                compiler
+                    .get_envs()
+                    .map(|(key, value)| (key.to_os_string(), value.to_os_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        if let Some(dir) = &self.launcher_cache_dir {
+            vars.push(("CCACHE_DIR".into(), dir.into()));
+            vars.push(("SCCACHE_DIR".into(), dir.into()));
+        }
+        if self.reproducible {
+            vars.push(("ZERO_AR_DATE".into(), "1".into()));
+        }
+        vars
+    }
+
+    /// Run `cmake --build` like the cmake crate does, sending each line of output to
+    /// [Build::log_file] and [Build::on_output_line].
+    /// The last lines are printed as cargo warnings when it fails.
+    fn run_build_step(
+        &self,
+        build_dir: &Path,
+        profile: &str,
+        build_target: &str,
+        vars: &[(OsString, OsString)],
+    ) -> Result<(), BuildError> {
+        let cmake = env::var_os("CMAKE").unwrap_or_else(|| "cmake".into());
+        let mut command = Command::new(&cmake);
+        command.current_dir(build_dir).envs(vars.iter().map(|(key, value)| (key, value)));
+        command
+            .arg("--build")
+            .arg(build_dir)
+            .arg("--target")
+            .arg(build_target)
+            .arg("--config")
+            .arg(profile);
+        // Make can share cargo's jobserver, where the cmake crate would pass it.
+        let jobserver = env::var_os("CARGO_MAKEFLAGS").filter(|_| {
+            build_dir.join("Makefile").exists()
+                && !cfg!(any(
+                    windows,
+                    target_os = "macos",
+                    target_os = "freebsd",
+                    target_os = "openbsd",
+                    target_os = "netbsd",
+                    target_os = "dragonfly"
+                ))
+        });
+        if let Some(makeflags) = jobserver {
+            command.env("MAKEFLAGS", makeflags);
+        } else if let Ok(jobs) = env::var("NUM_JOBS") {
+            command.arg("--parallel").arg(jobs);
+        }
+        if !self.build_args.is_empty() {
+            command.arg("--").args(&self.build_args);
+        }
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+        let mut log = match &self.log_file {
+            Some(path) => Some(std::fs::File::create(path)?),
+            None => None,
+        };
+        let mut child = command.spawn()?;
+        let (sender, receiver) = mpsc::channel::<String>();
+        let mut readers = vec![];
+        let outputs: [Option<Box<dyn Read + Send>>; 2] = [
+            child.stdout.take().map(|out| Box::new(out) as Box<dyn Read + Send>),
+            child.stderr.take().map(|err| Box::new(err) as Box<dyn Read + Send>),
+        ];
+        for output in outputs.into_iter().flatten() {
+            let sender = sender.clone();
+            readers.push(std::thread::spawn(move || {
+                let mut reader = io::BufReader::new(output);
+                let mut line = vec![];
+                while reader.read_until(b'\n', &mut line).is_ok_and(|n| n > 0) {
+                    let text = String::from_utf8_lossy(&line).trim_end().to_string();
+                    if sender.send(text).is_err() {
+                        break;
+                    }
+                    line.clear();
+                }
+            }));
+        }
+        drop(sender);
+
+        // Lines from stdout and stderr are handled in the order they arrive.
+        let mut tail = VecDeque::with_capacity(LOG_TAIL_LINES);
+        for line in receiver {
+            println!("{}", line);
+            if let Some(log) = log.as_mut() {
+                writeln!(log, "{}", line)?;
+            }
+            if let Some(callback) = &self.on_output_line {
+                callback(&line);
+            }
+            if tail.len() == LOG_TAIL_LINES {
+                tail.pop_front();
+            }
+            tail.push_back(line);
+        }
+        for reader in readers {
+            let _ = reader.join();
+        }
+
+        let status = child.wait()?;
+        if !status.success() {
+            print_log_tails(build_dir);
+            println!("cargo:warning=Last lines of cmake --build:");
+            for line in tail {
+                println!("cargo:warning={}", line);
+            }
+            return Err(BuildError::CommandFailed(format!(
+                "cmake --build {} exited with {}",
+                build_dir.display(),
+                status
+            )));
+        }
+        Ok(())
+    }
+
+    /// Copy `compile_commands.json` from the cmake build directory, see [Build::emit_compile_commands].
+    fn copy_compile_commands(&self, build_dir: &Path) -> Result<(), BuildError> {
+        let Some(dest) = &self.compile_commands_dest else {
+            return Ok(());
+        };
+        let compile_commands = build_dir.join("compile_commands.json");
+        if compile_commands.exists() {
+            std::fs::copy(compile_commands, dest)?;
+        }
+        Ok(())
+    }
+
+    /// Print the cache statistics of the compiler launcher as cargo warnings.
+    /// Launchers that don't support `--show-stats` are ignored.
+    fn print_launcher_stats(&self, launcher: &str) {
+        let mut command = Command::new(launcher);
+        command.arg("--show-stats");
+        if let Some(dir) = &self.launcher_cache_dir {
+            command.env("CCACHE_DIR", dir).env("SCCACHE_DIR", dir);
+        }
+        let Ok(output) = command.output() else {
+            return;
+        };
+        if !output.status.success() {
+            return;
+        }
+        let stats = String::from_utf8_lossy(&output.stdout);
+        for line in stats.lines().filter(|line| !line.trim().is_empty()) {
+            println!("cargo:warning={}: {}", launcher, line);
+        }
+    }
+
+    /// Strip the libraries in `lib_dir`, see [Build::strip].
+    fn strip_libs(&self, out_dir: &Path, lib_dir: &Path, target: &str) -> Result<(), BuildError> {
+        let host = self.host.as_deref().unwrap_or(target);
+        let built_strip = out_dir
+            .join("bin")
+            .join(format!("llvm-strip{}", env::consts::EXE_SUFFIX));
+        let strip: OsString = if Command::new("llvm-strip").arg("--version").output().is_ok() {
+            "llvm-strip".into()
+        } else if host == target && built_strip.exists() {
+            built_strip.into_os_string()
+        } else {
+            "strip".into()
+        };
+        // Apple's strip doesn't accept the GNU options.
+        let apple_strip = strip == "strip" && host.contains("apple");
+
+        let mut size_before = 0;
+        let mut size_after = 0;
+        for entry in std::fs::read_dir(lib_dir)?.flatten() {
+            let path = entry.path();
+            // Symlinks point to a library that is stripped on its own.
+            let metadata = std::fs::symlink_metadata(&path)?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            // The import libraries of MinGW targets are not stripped.
+            let kind = if lib_name(file_name, target, LinkKind::Static, None, None).is_some() {
+                LinkKind::Static
+            } else if lib_name(file_name, target, LinkKind::Dylib, None, None).is_some()
+                && !target.contains("windows")
+            {
+                LinkKind::Dylib
+            } else {
+                continue;
+            };
+
+            let status = Command::new(&strip)
+                .arg(match (kind, apple_strip) {
+                    (LinkKind::Static, false) => "--strip-debug",
+                    (LinkKind::Dylib, false) => "--strip-unneeded",
+                    (LinkKind::Static, true) => "-S",
+                    (LinkKind::Dylib, true) => "-x",
+                })
+                .arg(&path)
+                .status()?;
+            if !status.success() {
+                return Err(BuildError::CommandFailed(format!(
+                    "{} {} exited with {}",
+                    strip.to_string_lossy(),
+                    path.display(),
+                    status
+                )));
+            }
+            size_before += metadata.len();
+            size_after += std::fs::metadata(&path)?.len();
+        }
+
+        println!(
+            "cargo:warning=Stripped the LLVM libraries from {} MiB to {} MiB",
+            size_before / (1024 * 1024),
+            size_after / (1024 * 1024)
+        );
+        Ok(())
+    }
+
+    /// Check that the requested options can be used together.
+    fn validate(&self, target: &str) -> Result<(), BuildError> {
+        if let Some(runtime) = self.projects.iter().find(|p| RUNTIMES.contains(&p.as_str())) {
+            return Err(BuildError::InvalidConfig(format!(
+                "{} is a runtime, it must be enabled with enable_runtimes",
+                runtime
+            )));
+        }
+        if !self.runtimes.is_empty() && !self.projects().iter().any(|p| p == "clang") {
+            return Err(BuildError::InvalidConfig(
+                "enable_runtimes requires the clang project".to_string(),
+            ));
+        }
+        if self.component_shared {
+            if target.contains("windows") {
+                return Err(BuildError::InvalidConfig(format!(
+                    "component_shared is not supported on {}",
+                    target
+                )));
+            }
+            let dylib = self.build_dylib
+                || self.extra_defines.iter().any(|(key, value)| {
+                    (key == "LLVM_BUILD_LLVM_DYLIB" || key == "LLVM_LINK_LLVM_DYLIB") && value == "ON"
+                });
+            if dylib {
+                return Err(BuildError::InvalidConfig(
+                    "component_shared can't be used together with the libLLVM shared library".to_string(),
+                ));
+            }
+        }
+        if let Some(abi) = &self.float_abi {
+            if !["soft", "softfp", "hard"].contains(&abi.as_str()) {
+                return Err(BuildError::InvalidConfig(format!("unknown float ABI {:?}", abi)));
+            }
+            if !(target.starts_with("arm") || target.starts_with("thumb")) {
+                return Err(BuildError::InvalidConfig(format!(
+                    "float_abi is only supported on ARM targets, not on {}",
+                    target
+                )));
+            }
+        }
+        let doc_tool = match self.docs {
+            DocKind::None => None,
+            DocKind::Man | DocKind::Html => Some("sphinx-build"),
+            DocKind::Doxygen => Some("doxygen"),
+        };
+        if let Some(tool) = doc_tool {
+            if Command::new(tool).arg("--version").output().is_err() {
+                return Err(BuildError::MissingTool(tool));
+            }
+        }
+        if self.tune_cpu.is_some() && target.contains("msvc") {
+            return Err(BuildError::InvalidConfig(
+                "tune_cpu is not supported with MSVC".to_string(),
+            ));
+        }
+        if self.fat_lto_objects && self.lto == LtoMode::Thin {
+            return Err(BuildError::InvalidConfig(
+                "fat_lto_objects can't be used with ThinLTO".to_string(),
+            ));
+        }
+        if self.build_dylib && is_msvc_layout(target) {
+            return Err(BuildError::InvalidConfig(format!("build_dylib is not supported on {}", target)));
+        }
+        if !self.link_static && !self.build_dylib {
+            return Err(BuildError::InvalidConfig(
+                "link_static(false) requires build_dylib".to_string(),
+            ));
+        }
+        if self.component_shared && !self.pic && !target.contains("windows") {
+            return Err(BuildError::InvalidConfig(
+                "component_shared requires position-independent code".to_string(),
+            ));
+        }
+        let visual_studio = self.generator.as_deref().is_some_and(|g| g.starts_with("Visual Studio"));
+        if self.toolset.is_some() && !visual_studio {
+            return Err(BuildError::InvalidConfig(
+                "toolset requires a Visual Studio generator".to_string(),
+            ));
+        }
+        if self.clean_scratch_dir && self.thin_archives {
+            return Err(BuildError::InvalidConfig(
+                "clean_scratch_dir can't be used with thin archives".to_string(),
+            ));
+        }
+        if self.strip && (target.contains("msvc") || self.thin_archives) {
+            return Err(BuildError::InvalidConfig(
+                "strip is supported neither on MSVC targets nor with thin archives".to_string(),
+            ));
+        }
+        if self.thin_archives && (target.contains("msvc") || target.contains("apple")) {
+            return Err(BuildError::InvalidConfig(format!(
+                "thin_archives is not supported on {}",
+                target
+            )));
+        }
+        Ok(())
+    }
+
+    /// Get the directory of the LLVM libraries produced by a build in `out_dir`.
+    fn lib_dir(&self, out_dir: &Path) -> PathBuf {
+        if self.thin_archives {
+            self.build_dir(out_dir).join("lib")
+        } else {
+            out_dir.join("lib")
+        }
+    }
+
+    /// Get the absolute include directory of the build in `dir`, see [Build::include_dir].
+    fn resolved_include_dir(&self, dir: &Path) -> io::Result<PathBuf> {
+        match &self.include_dir {
+            Some(include_dir) => std::path::absolute(include_dir),
+            None => Ok(dir.join("include")),
+        }
+    }
+
+    /// Get the cmake build tree for a build in `out_dir`, see [Build::scratch_dir].
+    fn build_dir(&self, out_dir: &Path) -> PathBuf {
+        match &self.scratch_dir {
+            Some(dir) => absolute_path(dir).join("build"),
+            None => out_dir.join("build"),
+        }
+    }
+
+    /// Get a hash of the LLVM source code and of the resolved configuration.
+    /// [Build::build] skips cmake entirely when the hash matches the one of the last
+    /// successful build in the same output directory.
+    /// The hash doesn't depend on timestamps, so it can also be used as a key for external caches.
+    /// The source code is identified by its commit when it's a git checkout without local changes,
+    /// or by the version of the packaged crate, otherwise all of its files are hashed.
+    /// This will panic if any of the required environment variables are not set (see [Build::new]).
+    pub fn config_hash(&self) -> String {
+        let host = self.host.as_ref().expect("HOST not set");
+        let target = self.target.as_ref().expect("TARGET not set");
+        let profile = self.profile.as_ref().expect("PROFILE not set");
+
+        let mut hasher = Fnv1a::new();
+        hasher.write_u64(source_hash(&source_dir(), &self.projects(), &self.runtimes));
+        for value in [host, target, profile] {
+            hasher.write_str(value);
+        }
+        for value in [&self.generator, &self.toolset] {
+            hasher.write_str(value.as_deref().unwrap_or_default());
+        }
+        hasher.write_u64(self.bytes_per_link_job.unwrap_or_default() as u64);
+        for (key, value) in self.defines() {
+            hasher.write_str(&key);
+            hasher.write_str(&value);
+        }
+        // The prefix maps contain the paths of this machine, they are replaced by the option.
+        for flag in self.compiler_flags() {
+            if !flag.starts_with("-ffile-prefix-map=") {
+                hasher.write_str(&flag);
+            }
+        }
+        hasher.write_str(if self.reproducible { "reproducible" } else { "" });
+        // Stripping happens after cmake, but it changes the produced libraries.
+        hasher.write_str(if self.strip { "strip" } else { "" });
+        for arg in self.configure_args.iter().chain(self.build_args.iter()) {
+            hasher.write_str(arg);
+        }
+        format!("{:016x}", hasher.finish())
+    }
+
+    /// Get the value of `LLVM_PARALLEL_LINK_JOBS` for the build machine, see [Build::auto_link_jobs].
+    fn link_jobs(&self) -> u64 {
+        match (self.bytes_per_link_job, total_memory()) {
+            (Some(bytes_per_job), Some(memory)) => {
+                let cpus = std::thread::available_parallelism().map_or(1, |n| n.get() as u64);
+                (memory / bytes_per_job.max(1) as u64).clamp(1, cpus)
+            }
+            _ => 1,
+        }
+    }
+
+    /// Get the flags passed to both the C and the C++ compiler.
+    fn compiler_flags(&self) -> Vec<String> {
+        let mut flags = vec![];
+        if let Some(abi) = &self.float_abi {
+            flags.push(format!("-mfloat-abi={}", abi));
+        }
+        if self.fat_lto_objects && self.lto == LtoMode::Full {
+            flags.push("-ffat-lto-objects".to_string());
+        }
+        let target = self.target.as_deref().unwrap_or_default();
+        if let Some(cpu) = &self.tune_cpu {
+            if target.starts_with("x86_64") || target.starts_with("i686") || target.starts_with("i586") {
+                flags.push(format!("-march={}", cpu));
+            } else {
+                flags.push(format!("-mcpu={}", cpu));
+            }
+        }
+        let msvc = target.contains("msvc");
+        if self.reproducible && !msvc {
+            let source_dir = source_dir();
+            let source_root = source_dir.parent().unwrap_or(&source_dir);
+            flags.push(format!("-ffile-prefix-map={}=llvm-src", source_root.display()));
+            // The build directory contains the generated sources, e.g. the tablegen `.inc` files.
+            let out_dir = self.out_dir.as_ref().and_then(|dir| std::path::absolute(dir).ok());
+            if let Some(out_dir) = out_dir {
+                let build_dir = self.build_dir(&out_dir);
+                flags.push(format!("-ffile-prefix-map={}=llvm-build", build_dir.display()));
+            }
+        }
+        if let (Some(color), false) = (self.diagnostics_color, msvc) {
+            let when = if color { "always" } else { "never" };
+            flags.push(format!("-fdiagnostics-color={}", when));
+        }
+        flags
+    }
+
+    /// Get the cmake defines resolved from the configuration, including the ones added with [Build::define].
+    /// These are part of [Build::config_hash], so changing any of them rebuilds LLVM,
+    /// and they are written to `$OUT_DIR/llvm-build/defines.json` after each successful build.
+    pub fn defines(&self) -> BTreeMap<String, String> {
+        let on_off = |value: bool| if value { "ON" } else { "OFF" };
+        let mut defines = BTreeMap::new();
+        let mut define = |key: &str, value: &str| {
+            defines.insert(key.to_string(), value.to_string());
+        };
+
+        define("LLVM_ENABLE_LIBXML2", "OFF");
+        define("LLVM_ENABLE_LIBCXX", "OFF");
+        define("LLVM_ENABLE_PER_TARGET_RUNTIME_DIR", "OFF");
+        define("LLVM_LINK_LLVM_DYLIB", "OFF");
+        define("LLVM_BUILD_LLVM_DYLIB", on_off(self.build_dylib));
+        define("LLVM_ENABLE_PIC", on_off(self.pic));
+        define("LLVM_ENABLE_EH", "ON");
+        define("LLVM_ENABLE_FFI", "ON");
+        define("LLVM_ENABLE_RTTI", "ON");
+        define("LLVM_INCLUDE_DOCS", "OFF");
+        define("LLVM_INCLUDE_TESTS", "OFF");
+        define("LLVM_INCLUDE_BENCHMARKS", "OFF");
+        define("LLVM_INCLUDE_EXAMPLES", "OFF");
+        define("LLVM_INSTALL_UTILS", "OFF");
+        define("LLVM_ENABLE_BINDINGS", "OFF");
+        let force_on = |mode: CompressionMode| match mode {
+            CompressionMode::Off => "OFF",
+            CompressionMode::System | CompressionMode::Bundled => "FORCE_ON",
+        };
+        define("LLVM_ENABLE_ZLIB", force_on(self.zlib));
+        define("LLVM_ENABLE_ZSTD", force_on(self.zstd));
+        define("ZLIB_USE_STATIC_LIBS", on_off(self.zlib == CompressionMode::Bundled));
+        define("LLVM_USE_STATIC_ZSTD", on_off(self.zstd == CompressionMode::Bundled));
+        define("LLVM_ENABLE_LIBPFM", "OFF");
+        define("LLVM_ENABLE_Z3_SOLVER", on_off(self.z3));
+        define("LLVM_ENABLE_CURL", on_off(self.curl));
+        define("LLVM_ENABLE_LIBEDIT", on_off(self.libedit));
+        define("LLVM_OPTIMIZED_TABLEGEN", "ON");
+        define("LLVM_APPEND_VC_REV", on_off(self.append_vc_rev && !self.reproducible));
+        define("BUILD_SHARED_LIBS", on_off(self.component_shared));
+        // LLVM passes "-flto=full" for "Full", which GCC doesn't understand, "ON" is "-flto".
+        define(
+            "LLVM_ENABLE_LTO",
+            match self.lto {
+                LtoMode::Off => "OFF",
+                LtoMode::Thin => "Thin",
+                LtoMode::Full => "ON",
+            },
+        );
+        define("CMAKE_EXPORT_COMPILE_COMMANDS", "ON");
+
+        let projects = self.projects();
+        if !projects.is_empty() {
+            define("LLVM_ENABLE_PROJECTS", &projects.join(";"));
+        }
+        if !self.runtimes.is_empty() {
+            define("LLVM_ENABLE_RUNTIMES", &self.runtimes.join(";"));
+        }
+        if let (true, Some(z3_dir)) = (self.z3, &self.z3_dir) {
+            define("LLVM_Z3_INSTALL_DIR", &absolute_path(z3_dir).to_string_lossy());
+        }
+        let target = self.target.as_deref().unwrap_or_default();
+        if self.reproducible {
+            define("LLVM_ENABLE_TERMINFO", "OFF");
+        }
+        // Apple's ar has no deterministic mode, it uses ZERO_AR_DATE instead, and MSVC doesn't use ar.
+        let deterministic_ar = self.reproducible && !target.contains("apple") && !target.contains("msvc");
+        if self.thin_archives || deterministic_ar {
+            let thin = if self.thin_archives { "T" } else { "" };
+            let deterministic = if deterministic_ar { "D" } else { "" };
+            for lang in ["C", "CXX"] {
+                define(
+                    &format!("CMAKE_{}_ARCHIVE_CREATE", lang),
+                    &format!("<CMAKE_AR> qc{}{} <TARGET> <LINK_FLAGS> <OBJECTS>", thin, deterministic),
+                );
+                define(
+                    &format!("CMAKE_{}_ARCHIVE_APPEND", lang),
+                    &format!("<CMAKE_AR> q{}{} <TARGET> <LINK_FLAGS> <OBJECTS>", thin, deterministic),
+                );
+                if deterministic_ar {
+                    define(&format!("CMAKE_{}_ARCHIVE_FINISH", lang), "<CMAKE_RANLIB> -D <TARGET>");
+                }
+            }
+        }
+        if let Some(version) = &self.osx_deployment_target {
+            define("CMAKE_OSX_DEPLOYMENT_TARGET", version);
+        }
+        if !self.osx_architectures.is_empty() {
+            define("CMAKE_OSX_ARCHITECTURES", &self.osx_architectures.join(";"));
+        }
+        if self.docs != DocKind::None {
+            define("LLVM_INCLUDE_DOCS", "ON");
+            define("LLVM_BUILD_DOCS", "ON");
+            define("LLVM_ENABLE_SPHINX", on_off(matches!(self.docs, DocKind::Man | DocKind::Html)));
+            define("SPHINX_OUTPUT_MAN", on_off(self.docs == DocKind::Man));
+            define("SPHINX_OUTPUT_HTML", on_off(self.docs == DocKind::Html));
+            define("SPHINX_WARNINGS_AS_ERRORS", "OFF");
+            define("LLVM_ENABLE_DOXYGEN", on_off(self.docs == DocKind::Doxygen));
+        }
+        if let Some(launcher) = &self.compiler_launcher {
+            define("CMAKE_C_COMPILER_LAUNCHER", launcher);
+            define("CMAKE_CXX_COMPILER_LAUNCHER", launcher);
+        }
+        if self.relative_rpath && !target.contains("windows") {
+            let origin = if target.contains("apple") {
+                "@loader_path"
+            } else {
+                "$ORIGIN"
+            };
+            // LLVM doesn't add its own `$ORIGIN/../lib` when CMAKE_INSTALL_RPATH is set.
+            define("CMAKE_INSTALL_RPATH", &format!("{0};{0}/../lib", origin));
+            define("CMAKE_BUILD_WITH_INSTALL_RPATH", "ON");
+        }
+        let path_list = |dirs: &[PathBuf]| {
+            dirs.iter()
+                .map(|dir| absolute_path(dir).to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+                .join(";")
+        };
+        if !self.include_paths.is_empty() {
+            define("CMAKE_INCLUDE_PATH", &path_list(&self.include_paths));
+        }
+        if !self.library_paths.is_empty() {
+            define("CMAKE_LIBRARY_PATH", &path_list(&self.library_paths));
+        }
+        if !self.tools.is_empty() {
+            define("LLVM_DISTRIBUTION_COMPONENTS", &self.tools.join(";"));
+        }
+        if let Some(dump) = self.dump {
+            define("LLVM_ENABLE_DUMP", on_off(dump));
+        }
+        define(
+            "LLVM_ABI_BREAKING_CHECKS",
+            match self.abi_breaking_checks {
+                AbiBreakingChecks::WithAsserts => "WITH_ASSERTS",
+                AbiBreakingChecks::ForceOn => "FORCE_ON",
+                AbiBreakingChecks::ForceOff => "FORCE_OFF",
+            },
+        );
+        define("LLVM_ENABLE_EXPENSIVE_CHECKS", on_off(self.expensive_checks));
+        if self.expensive_checks {
+            define("LLVM_ENABLE_ASSERTIONS", "ON");
+        }
+        if let Some(color) = self.diagnostics_color {
+            define("CMAKE_COLOR_DIAGNOSTICS", on_off(color));
+        }
+        define("CMAKE_UNITY_BUILD", on_off(self.unity_build));
+        if let (true, Some(size)) = (self.unity_build, self.unity_batch_size) {
+            define("CMAKE_UNITY_BUILD_BATCH_SIZE", &size.to_string());
+        }
+        if let Some(compiler) = &self.c_compiler {
+            define("CMAKE_C_COMPILER", &program_path(compiler).to_string_lossy());
+        }
+        if let Some(compiler) = &self.cxx_compiler {
+            define("CMAKE_CXX_COMPILER", &program_path(compiler).to_string_lossy());
+        }
+        let native_compilers = [("C", &self.host_c_compiler), ("CXX", &self.host_cxx_compiler)];
+        let native_flags = native_compilers
+            .iter()
+            .filter_map(|(lang, compiler)| {
+                let compiler = program_path(compiler.as_ref()?);
+                Some(format!("-DCMAKE_{}_COMPILER={}", lang, compiler.display()))
+            })
+            .chain(self.native_toolchain_flags.iter().cloned())
+            .collect::<Vec<_>>();
+        if !native_flags.is_empty() {
+            define("CROSS_TOOLCHAIN_FLAGS_NATIVE", &native_flags.join(";"));
+        }
+
+        for (key, value) in self.extra_defines.iter() {
+            define(key, value);
+        }
+        defines
+    }
+
+    /// Create the [Artifacts] from an existing LLVM build or install directory, without running cmake.
+    /// `dir` must contain the `bin`, `lib` and `include` directories, `llvm-config` is
+    /// used as in [Build::build] to find the libraries, unless the host is not the target.
+    /// Only the host, the target and the library options are used, see [Build::new].
+    /// This will panic if no libraries are found, see [BuildError::NoLibrariesFound].
+    pub fn from_build_dir(&self, dir: &Path) -> Artifacts {
+        let target = self.target.as_ref().expect("TARGET not set").as_str();
+        let dir = std::path::absolute(dir).expect("Failed to resolve the build directory");
+        self.discover(&dir, dir.join("lib"), target)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Get the cargo instructions that [Artifacts::print_cargo_metadata] is expected to print,
+    /// using only the configuration, without building LLVM.
+    /// The LLVM libraries are only known after the build, so they are reported
+    /// as [PlannedLib::PendingBuild] for each of the [Build::required_libs], and the components
+    /// added by other options, e.g. [Build::with_remarks].
+    /// With [Build::link_static] set to `false`, only libLLVM is linked.
+    pub fn cargo_instructions(&self) -> Result<CargoInstructions, BuildError> {
+        let out_dir = self.out_dir.as_ref().ok_or(BuildError::MissingParameter("OUT_DIR"))?;
+        let out_dir = std::path::absolute(out_dir)?;
+        let kind = if self.component_shared {
+            LinkKind::Dylib
+        } else {
+            LinkKind::Static
+        };
+
+        let mut link_search = vec![self.lib_dir(&out_dir)];
+        link_search.extend(self.optional_system_lib_dirs());
+
+        let components = if self.tools.is_empty() {
+            self.components()
+        } else {
+            vec![]
+        };
+        let mut link_libs = components
+            .iter()
+            .map(|component| PlannedLib::PendingBuild {
+                kind,
+                component: component.clone(),
+            })
+            .collect::<Vec<_>>();
+        link_libs.extend(self.optional_system_libs().into_iter().map(|name| PlannedLib::Known {
+            kind: LinkKind::Dylib,
+            name,
+        }));
+        link_libs.extend(self.compression_libs(CompressionMode::Bundled).into_iter().map(|name| {
+            PlannedLib::Known {
+                kind: LinkKind::Static,
+                name,
+            }
+        }));
+        // libLLVM already links everything else.
+        if !self.link_static {
+            link_libs = vec![PlannedLib::Known {
+                kind: LinkKind::Dylib,
+                name: "LLVM".to_string(),
+            }];
+        }
+
+        Ok(CargoInstructions {
+            link_search,
+            link_libs,
+            include_dirs: vec![self.resolved_include_dir(&out_dir)?],
+            lib_dirs: vec![self.lib_dir(&out_dir)],
+        })
+    }
+
+    /// Get the enabled projects, including the ones added by other options, e.g. [Build::mlir].
+    fn projects(&self) -> Vec<String> {
+        let mut projects = self.projects.clone();
+        if self.mlir && !projects.iter().any(|p| p == "mlir") {
+            projects.push("mlir".to_string());
+        }
+        projects
+    }
+
+    /// Get the LLVM components to link, the [Build::required_libs] and the ones added by other options.
+    fn components(&self) -> Vec<String> {
+        let mut components = self.required_libs.clone();
+        if components.iter().any(|c| c == "all") {
+            return components;
+        }
+        let extra = [
+            (self.remarks, "remarks"),
+            (self.passes, "passes"),
+            (self.passes, "ipo"),
+        ];
+        for (enabled, component) in extra {
+            if enabled && !components.iter().any(|c| c == component) {
+                components.push(component.to_string());
+            }
+        }
+        components
+    }
+
+    /// Get the paths of the tools built with [Build::tools_only] in the install directory `dir`.
+    fn binaries(&self, dir: &Path, target: &str) -> Vec<PathBuf> {
+        let suffix = if target.contains("windows") { ".exe" } else { "" };
+        self.tools
+            .iter()
+            .map(|tool| dir.join("bin").join(format!("{}{}", tool, suffix)))
+            .collect()
+    }
+
+    /// Get the system libraries required by the enabled optional dependencies.
+    /// These are not needed when only the tools are built, see [Build::tools_only].
+    fn optional_system_libs(&self) -> Vec<String> {
+        let mut libs = vec![];
+        if !self.tools.is_empty() {
+            return libs;
+        }
+        if self.z3 {
+            libs.push("z3".to_string());
+        }
+        if self.curl {
+            libs.push("curl".to_string());
+        }
+        if self.libedit {
+            libs.push("edit".to_string());
+        }
+        libs.extend(self.compression_libs(CompressionMode::System));
+        libs
+    }
+
+    /// Get the compression libraries used with the given mode, see [Build::compression].
+    fn compression_libs(&self, mode: CompressionMode) -> Vec<String> {
+        let mut libs = vec![];
+        if !self.tools.is_empty() {
+            return libs;
+        }
+        let target = self.target.as_deref().unwrap_or_default();
+        if self.zlib == mode {
+            libs.push(if target.contains("msvc") { "zlib" } else { "z" }.to_string());
+        }
+        if self.zstd == mode {
+            libs.push("zstd".to_string());
+        }
+        libs
+    }
+
+    /// Get the search directories of the enabled optional dependencies.
+    fn optional_system_lib_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = vec![];
+        if !self.tools.is_empty() {
+            return dirs;
+        }
+        if let (true, Some(z3_dir)) = (self.z3, &self.z3_dir) {
+            dirs.push(absolute_path(z3_dir).join("lib"));
+        }
+        dirs
+    }
+
+    /// Find the libraries and directories of the LLVM build in `dir`.
+    /// Finding no libraries is an error, unless only cmake's configure step was run.
+    fn discover(&self, dir: &Path, lib_dir: PathBuf, target: &str) -> Result<Artifacts, BuildError> {
+        let include_dir = self.resolved_include_dir(dir)?;
+        let version = std::fs::read_to_string(include_dir.join("llvm/Config/llvm-config.h"))
+            .ok()
+            .and_then(|header| llvm_version(&header));
+        let abi_breaking_checks = std::fs::read_to_string(include_dir.join("llvm/Config/abi-breaking.h"))
+            .ok()
+            .and_then(|header| {
+                header.lines().find_map(|line| {
+                    let value = line.strip_prefix("#define LLVM_ENABLE_ABI_BREAKING_CHECKS")?;
+                    Some(value.trim() == "1")
+                })
+            });
+
+        let mut libs = vec![];
+        let mut system_libs = vec![];
+
+        let llvm_config = dir
+            .join("bin")
+            .join(format!("llvm-config{}", env::consts::EXE_SUFFIX));
+        // llvm-config may not have been built yet after a configure-only run,
+        // and it can't run on the host when it was cross-compiled.
+        let cross = self.host.as_deref().is_some_and(|host| host != target);
+        let use_llvm_config = self.tools.is_empty() && llvm_config.exists() && !cross;
+        let link_kind = if self.component_shared {
+            LinkKind::Dylib
+        } else {
+            LinkKind::Static
+        };
+        let (prefix, suffix) = (self.strip_prefix.as_deref(), self.strip_suffix.as_deref());
+        if !use_llvm_config && self.tools.is_empty() {
+            if !self.reconfigure_only {
+                println!(
+                    "cargo:warning=llvm-config can't be used in {}, the libraries found in {} may not be in link order",
+                    dir.display(),
+                    lib_dir.display()
+                );
+            }
+            let found = discover_libs(&lib_dir, target, link_kind, prefix, suffix);
+            libs.extend(found.into_iter().map(|lib| lib.name));
+        }
+        let run_llvm_config = |arg: &str| {
+            if !use_llvm_config {
+                return Ok(String::new());
+            }
+            let output = Command::new(&llvm_config)
+                .arg(arg)
+                .args(self.components())
+                .arg(match link_kind {
+                    LinkKind::Static => "--link-static",
+                    LinkKind::Dylib => "--link-shared",
+                })
+                .output()?;
+            if !output.status.success() {
+                return Err(BuildError::CommandFailed(format!(
+                    "llvm-config {} exited with {}: {}",
+                    arg,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        };
+        let unexpected = |lib: &str| {
+            BuildError::CommandFailed(format!(
+                "llvm-config reported an unexpected library name for {}: {}",
+                target, lib
+            ))
+        };
+
+        // The LLVM libraries and the system libraries are queried separately,
+        // on Windows both are reported with the same `.lib` extension.
+        let libnames = run_llvm_config("--libnames")?;
+        for lib in libnames.split(&[' ', '\n']).filter(|s| !s.is_empty()) {
+            let lib_name = lib_name(lib, target, link_kind, prefix, suffix);
+            libs.push(lib_name.ok_or_else(|| unexpected(lib))?.to_string());
+        }
+
+        let mut duplicate_libs = vec![];
+        let system_libnames = run_llvm_config("--system-libs")?;
+        for lib in system_libnames.split(&[' ', '\n']).filter(|s| !s.is_empty()) {
+            let lib_name = match lib.strip_prefix("-l") {
+                Some(lib_name) => lib_name,
+                None if is_msvc_layout(target) => {
+                    lib.strip_suffix(".lib").ok_or_else(|| unexpected(lib))?
+                }
+                None => return Err(unexpected(lib)),
+            };
+            if system_libs.iter().any(|l| l == lib_name) {
+                duplicate_libs.push(lib_name.to_string());
+            } else {
+                system_libs.push(lib_name.to_string());
+            }
+        }
+
+        // MLIR is not known to llvm-config, its libraries depend on the LLVM ones.
+        if self.mlir && self.tools.is_empty() {
+            let mlir_libs = mlir_libs(&lib_dir, target, link_kind);
+            libs.retain(|lib| !mlir_libs.contains(lib));
+            libs.splice(0..0, mlir_libs);
+        }
+
+        for lib in self.optional_system_libs() {
+            if !system_libs.contains(&lib) {
+                system_libs.push(lib);
+            }
+        }
+        // llvm-config reports the bundled compression libraries like the other system libraries.
+        let static_system_libs = self.compression_libs(CompressionMode::Bundled);
+        system_libs.retain(|lib| !static_system_libs.contains(lib));
+        let system_lib_dirs = self.optional_system_lib_dirs();
+
+        // The system libraries are only known from llvm-config, and shared libraries link their own.
+        if use_llvm_config && link_kind == LinkKind::Static {
+            for warning in system_lib_warnings(target, &libs, &system_libs, &duplicate_libs) {
+                println!("cargo:warning={}", warning);
+            }
+        }
+
+        // Install trees and build trees have different layouts.
+        let docs_dirs: &[&str] = match self.docs {
+            DocKind::None => &[],
+            DocKind::Man => &["share/man/man1", "docs/man"],
+            DocKind::Html => &["share/doc/LLVM/llvm/html", "docs/html"],
+            DocKind::Doxygen => &["share/doc/LLVM/llvm/doxygen-html", "docs/doxygen/html"],
+        };
+        let docs_dir = docs_dirs.iter().map(|d| dir.join(d)).find(|d| d.is_dir());
+
+        if libs.is_empty() && self.tools.is_empty() && !self.reconfigure_only {
+            return Err(BuildError::NoLibrariesFound { lib_dir });
+        }
+        if self.verify_artifacts && link_kind == LinkKind::Static {
+            for lib in discover_libs(&lib_dir, target, link_kind, prefix, suffix) {
+                if !is_valid_archive(&lib.path)? {
+                    return Err(BuildError::CorruptArtifact { path: lib.path });
+                }
+            }
+        }
+
+        // Both libLLVM.so and libLLVM-15.so are installed on linux, the unversioned one is preferred.
+        let shared_lib = if self.build_dylib {
+            let mut names = discover_libs(&lib_dir, target, LinkKind::Dylib, None, None)
+                .into_iter()
+                .map(|lib| lib.name)
+                .filter(|name| name == "LLVM" || name.starts_with("LLVM-"))
+                .collect::<Vec<_>>();
+            names.sort_by_key(|name| name != "LLVM");
+            names.into_iter().next()
+        } else {
+            None
+        };
+
+        // In a build tree, the MLIR headers are split between the sources and the generated ones.
+        let mut include_dirs = vec![include_dir];
+        let mlir_generated = dir.join("tools").join("mlir").join("include");
+        if self.mlir && mlir_generated.is_dir() {
+            include_dirs.push(mlir_generated);
+            include_dirs.push(source_dir().join("../mlir/include"));
+        }
+
+        // The resource directory is named after the full version in LLVM 15, e.g. `lib/clang/15.0.7`.
+        let clang_dir = dir.join("lib").join("clang");
+        let versioned = version.as_ref().map(|version| clang_dir.join(version));
+        let clang_resource_dir = versioned.filter(|dir| dir.join("include").is_dir()).or_else(|| {
+            let mut dirs = std::fs::read_dir(&clang_dir)
+                .ok()?
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|dir| dir.join("include").is_dir())
+                .collect::<Vec<_>>();
+            dirs.sort();
+            dirs.pop()
+        });
+
+        let files = discover_libs(&lib_dir, target, link_kind, prefix, suffix);
+        let lib_sizes = libs
+            .iter()
+            .filter_map(|name| {
+                let lib = files.iter().find(|lib| &lib.name == name)?;
+                let size = std::fs::metadata(&lib.path).ok()?.len();
+                Some((name.clone(), size))
+            })
+            .collect::<Vec<_>>();
+        if self.verbose {
+            let total = lib_sizes.iter().map(|(_, size)| size).sum::<u64>();
+            println!(
+                "cargo:warning={} LLVM libraries, {} MiB in total",
+                lib_sizes.len(),
+                total / (1024 * 1024)
+            );
+        }
+
+        let runtime_libs = if self.runtimes.is_empty() {
+            vec![]
+        } else {
+            find_runtime_libs(&lib_dir)
+        };
+
+        Ok(Artifacts {
+            include_dirs,
+            lib_dirs: vec![lib_dir],
+            libs,
+            link_kind,
+            runtime_libs,
+            system_libs,
+            static_system_libs,
+            system_lib_dirs,
+            docs_dir,
+            link_group: link_kind == LinkKind::Static
+                && !target.contains("msvc")
+                && !target.contains("apple")
+                && self.link_group.unwrap_or(true),
+            whole_archive: link_kind == LinkKind::Static && self.whole_archive,
+            version,
+            prefix: dir.to_path_buf(),
+            abi_breaking_checks,
+            link_shared_lib: !self.link_static && shared_lib.is_some(),
+            shared_lib,
+            binaries: self.binaries(dir, target),
+            clang_resource_dir,
+            lib_sizes,
+            // `dir` is either an output directory or a cmake build tree.
+            cmake_cache: [self.build_dir(dir), dir.to_path_buf()]
+                .into_iter()
+                .map(|dir| dir.join("CMakeCache.txt"))
+                .find(|path| path.exists()),
+        })
+    }
+}
+
+impl Default for Build {
+    fn default() -> Self {
+        let host = env::var("HOST").ok();
+        let target = env::var("TARGET").ok();
+        let out_dir = env::var_os("OUT_DIR").map(|s| llvm_build_dir(Path::new(&s)));
+        let profile = env::var("PROFILE").ok();
+        let required_libs = vec!["all".to_string()];
+
+        Self {
+            host,
+            target,
+            out_dir,
+            profile,
+            required_libs,
+            projects: vec![],
+            runtimes: vec![],
+            z3: false,
+            z3_dir: None,
+            reconfigure_only: false,
+            extra_defines: vec![],
+            configure_args: vec![],
+            build_args: vec![],
+            strip_prefix: None,
+            strip_suffix: None,
+            dump: None,
+            component_shared: false,
+            float_abi: None,
+            thin_archives: false,
+            osx_deployment_target: None,
+            osx_architectures: vec![],
+            compiler_launcher: None,
+            launcher_cache_dir: None,
+            strip: false,
+            lto: LtoMode::Off,
+            fat_lto_objects: false,
+            docs: DocKind::None,
+            compile_commands_dest: None,
+            relative_rpath: false,
+            append_vc_rev: false,
+            include_dir: None,
+            curl: false,
+            libedit: false,
+            pic: true,
+            link_group: None,
+            whole_archive: false,
+            generator: None,
+            toolset: None,
+            cache_mismatch: CacheMismatchPolicy::Clean,
+            c_compiler: None,
+            cxx_compiler: None,
+            host_c_compiler: None,
+            host_cxx_compiler: None,
+            native_toolchain_flags: vec![],
+            unity_build: false,
+            unity_batch_size: None,
+            abi_breaking_checks: AbiBreakingChecks::WithAsserts,
+            bytes_per_link_job: None,
+            build_dylib: false,
+            link_static: true,
+            tools: vec![],
+            expensive_checks: false,
+            verify_artifacts: false,
+            zlib: CompressionMode::Off,
+            zstd: CompressionMode::Off,
+            remarks: false,
+            passes: false,
+            include_paths: vec![],
+            library_paths: vec![],
+            mlir: false,
+            verbose: false,
+            diagnostics_color: None,
+            tune_cpu: None,
+            install_components: vec![],
+            reproducible: false,
+            log_file: None,
+            on_output_line: None,
+            scratch_dir: None,
+            clean_scratch_dir: false,
+        }
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::MissingParameter(name) => write!(f, "{} not set", name),
+            BuildError::CorruptBuildDir { path } => write!(
+                f,
+                "The LLVM build directory {} was left incomplete by an interrupted build, remove it and build again",
+                path.display()
+            ),
+            BuildError::InvalidConfig(message) => write!(f, "Invalid configuration: {}", message),
+            BuildError::CommandFailed(message) => write!(f, "Command failed: {}", message),
+            BuildError::MissingTool(tool) => write!(f, "{} is required but it was not found", tool),
+            BuildError::NoLibrariesFound { lib_dir } => write!(
+                f,
+                "The configuration produced no linkable libraries in {}, check the required libraries \
+                 and that the build is not limited to tools or to a different kind of library (static or shared)",
+                lib_dir.display()
+            ),
+            BuildError::CorruptArtifact { path } => write!(
+                f,
+                "The library {} is corrupt, remove the LLVM build directory and build again",
+                path.display()
+            ),
+            BuildError::CacheMismatch { key, cached, requested } => write!(
+                f,
+                "The cmake cache has {}={}, but {} was requested",
+                key, cached, requested
+            ),
+            BuildError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BuildError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BuildError {
+    fn from(e: io::Error) -> Self {
+        BuildError::Io(e)
+    }
+}
+
+impl Artifacts {
+    /// Get the include directory.
+    /// For merged artifacts this is the include directory of the first build, see [Artifacts::include_dirs].
+    pub fn include(&self) -> &Path {
+        &self.include_dirs[0]
+    }
+
+    /// Get all the include directories.
+    pub fn include_dirs(&self) -> &[PathBuf] {
+        &self.include_dirs
+    }
+
+    /// Get the headers of the LLVM C API, the `.h` files under `llvm-c` in the include directories,
+    /// e.g. to pass each of them to bindgen.
+    /// The paths are sorted, and include the subdirectories, e.g. `llvm-c/Transforms/PassBuilder.h`.
+    pub fn c_api_headers(&self) -> Vec<PathBuf> {
+        let mut headers = vec![];
+        for dir in self.include_dirs.iter() {
+            walk_files(&dir.join("llvm-c"), &mut headers);
+        }
+        headers.retain(|path| path.extension().is_some_and(|ext| ext == "h"));
+        headers.sort();
+        headers.dedup();
+        headers
+    }
+
+    /// Get the lib directory.
+    /// For merged artifacts this is the lib directory of the first build, see [Artifacts::lib_dirs].
+    pub fn lib(&self) -> &Path {
+        &self.lib_dirs[0]
+    }
+
+    /// Get all the lib directories.
+    pub fn lib_dirs(&self) -> &[PathBuf] {
+        &self.lib_dirs
+    }
+
+    /// Get the directory of the LLVM tools, e.g. `llvm-config`.
+    /// For merged artifacts this is the bin directory of the first build.
+    pub fn bin(&self) -> PathBuf {
+        self.prefix.join("bin")
+    }
+
+    /// Get the list of libraries.
+    pub fn libs(&self) -> &[String] {
+        &self.libs
+    }
+
+    /// Get the paths of the tools built with [Build::tools_only].
+    pub fn binaries(&self) -> &[PathBuf] {
+        &self.binaries
+    }
+
+    /// Get the resource directory of the clang built with [Build::enable_projects], e.g. `lib/clang/15.0.7`,
+    /// it contains the builtin headers like `stddef.h`, see `clang -resource-dir`.
+    pub fn clang_resource_dir(&self) -> Option<&Path> {
+        self.clang_resource_dir.as_deref()
+    }
+
+    /// Get the size in bytes of each of the [Artifacts::libs] found in the lib directory.
+    pub fn lib_sizes(&self) -> &[(String, u64)] {
+        &self.lib_sizes
+    }
+
+    /// Get the total size in bytes of the [Artifacts::lib_sizes].
+    pub fn total_size(&self) -> u64 {
+        self.lib_sizes.iter().map(|(_, size)| size).sum()
+    }
+
+    /// Read the `CMakeCache.txt` of the build, e.g. to check which dependencies were detected.
+    /// This fails if the build tree was removed, see [Build::clean_scratch_dir].
+    pub fn cmake_cache(&self) -> io::Result<String> {
+        match &self.cmake_cache {
+            Some(path) => std::fs::read_to_string(path),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "CMakeCache.txt not found")),
+        }
+    }
+
+    /// Read the entries of the `CMakeCache.txt` of the build, see [Artifacts::cmake_cache].
+    /// The keys don't include the types, e.g. `LLVM_ENABLE_ZLIB` for `LLVM_ENABLE_ZLIB:STRING=OFF`.
+    pub fn cmake_cache_entries(&self) -> io::Result<BTreeMap<String, String>> {
+        match &self.cmake_cache {
+            Some(path) => read_cmake_cache(path),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "CMakeCache.txt not found")),
+        }
+    }
+
+    /// Get the LLVM components of the [Artifacts::libs], e.g. "core" for `LLVMCore`
+    /// or "x86codegen" for `LLVMX86CodeGen`, these are the names used by `llvm-config`.
+    pub fn components(&self) -> Vec<String> {
+        self.libs
+            .iter()
+            .filter_map(|lib| lib.strip_prefix("LLVM"))
+            .filter(|name| !name.is_empty() && !name.starts_with('-'))
+            .map(|name| name.to_lowercase())
+            .collect()
+    }
+
+    /// Get the static libraries, these are the [Artifacts::libs] unless they are shared,
+    /// see [Build::component_shared].
+    pub fn static_libs(&self) -> &[String] {
+        match self.link_kind {
+            LinkKind::Static => &self.libs,
+            LinkKind::Dylib => &[],
+        }
+    }
+
+    /// Get the link name of the libLLVM shared library built with [Build::build_dylib], e.g. "LLVM".
+    pub fn shared_lib(&self) -> Option<&str> {
+        self.shared_lib.as_deref()
+    }
+
+    /// Get the directory of the documentation built with [Build::build_docs], if any.
+    pub fn docs_dir(&self) -> Option<&Path> {
+        self.docs_dir.as_deref()
+    }
+
+    /// Get how the libraries in [Artifacts::libs] are linked.
+    pub fn link_kind(&self) -> LinkKind {
+        self.link_kind
+    }
+
+    /// Get the paths of the runtime libraries built with [Build::enable_runtimes],
+    /// e.g. the compiler-rt builtins archive.
+    pub fn runtime_libs(&self) -> &[PathBuf] {
+        &self.runtime_libs
+    }
+
+    /// Get the list of system libraries LLVM depends on, e.g. "pthread" or "z3".
+    /// These are linked dynamically.
+    pub fn system_libs(&self) -> &[String] {
+        &self.system_libs
+    }
+
+    /// Get the list of system libraries linked statically, see [CompressionMode::Bundled].
+    pub fn static_system_libs(&self) -> &[String] {
+        &self.static_system_libs
+    }
+
+    /// Get the LLVM version, e.g. "15.0.7", read from `llvm/Config/llvm-config.h` in the include directory.
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// Get whether LLVM was built with the ABI breaking checks, see [Build::abi_breaking_checks].
+    /// This is read from `llvm/Config/abi-breaking.h` in the include directory.
+    pub fn abi_breaking_checks(&self) -> Option<bool> {
+        self.abi_breaking_checks
+    }
+
+    /// Write a pkg-config file describing these artifacts to `dest`, e.g. `$OUT_DIR/llvm.pc`,
+    /// so that other build systems can link against the same LLVM.
+    /// The system libraries are listed in `Libs.private`.
+    pub fn write_pkg_config(&self, dest: &Path) -> io::Result<()> {
+        fn quote(arg: String) -> String {
+            if arg.contains(char::is_whitespace) {
+                format!("\"{}\"", arg)
+            } else {
+                arg
+            }
+        }
+        let join = |args: Vec<String>| args.into_iter().map(quote).collect::<Vec<_>>().join(" ");
+
+        let cflags = self.include_dirs.iter().map(|dir| format!("-I{}", dir.display())).collect();
+        let mut libs = self
+            .lib_dirs
+            .iter()
+            .map(|dir| format!("-L{}", dir.display()))
+            .collect::<Vec<_>>();
+        libs.extend(self.libs.iter().map(|lib| format!("-l{}", lib)));
+        let mut libs_private = self
+            .system_lib_dirs
+            .iter()
+            .map(|dir| format!("-L{}", dir.display()))
+            .collect::<Vec<_>>();
+        libs_private.extend(
+            self.system_libs
+                .iter()
+                .chain(self.static_system_libs.iter())
+                .map(|lib| format!("-l{}", lib)),
+        );
+
+        let contents = format!(
+            "Name: LLVM\n\
+            Description: The LLVM compiler infrastructure\n\
+            Version: {}\n\
+            Libs: {}\n\
+            Libs.private: {}\n\
+            Cflags: {}\n",
+            self.version.as_deref().unwrap_or("0"),
+            join(libs),
+            join(libs_private),
+            join(cflags),
+        );
+        std::fs::write(dest, contents)
+    }
+
+    /// Archive the `include`, `lib` and `bin` directories of the build into `dest`,
+    /// so that the install tree can be cached and reused, e.g. with [Build::from_build_dir].
+    /// The format is chosen by the extension of `dest`, either `.zip` or `.tar.gz`/`.tgz`.
+    /// This runs `tar`, or `zip` to create zip files on platforms other than windows.
+    /// For merged artifacts only the first build is archived.
+    pub fn package(&self, dest: &Path) -> io::Result<()> {
+        let dest = std::path::absolute(dest)?;
+        let name = dest.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let mut command = if name.ends_with(".zip") {
+            if cfg!(windows) {
+                let mut command = Command::new("tar");
+                command.arg("-a").arg("-cf").arg(&dest);
+                command
+            } else {
+                let mut command = Command::new("zip");
+                command.arg("-r").arg("-q").arg(&dest);
+                command
+            }
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            let mut command = Command::new("tar");
+            command.arg("-czf").arg(&dest);
+            command
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unsupported archive format: {}", dest.display()),
+            ));
+        };
+        command.current_dir(&self.prefix);
+        for dir in ["include", "lib", "bin"] {
+            if self.prefix.join(dir).is_dir() {
+                command.arg(dir);
+            }
+        }
+
+        let status = command.status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "Failed to create {}, {:?} exited with {}",
+                dest.display(),
+                command.get_program(),
+                status
+            )));
+        }
+        Ok(())
+    }
+
+    /// Merge the artifacts of another build into these, e.g. a separate clang build.
+    /// The lib directories of both builds are added to the search path.
+    /// This will panic if the two builds have a different [LinkKind].
+    /// Libraries are concatenated, `other`'s after `self`'s, and when a library appears in both
+    /// only the last occurrence is kept, so that it still comes after the libraries depending on it.
+    pub fn merge(self, other: Artifacts) -> Artifacts {
+        fn dedup_first<T: PartialEq>(items: Vec<T>) -> Vec<T> {
+            let mut unique = Vec::with_capacity(items.len());
+            for item in items {
+                if !unique.contains(&item) {
+                    unique.push(item);
+                }
+            }
+            unique
+        }
+        fn dedup_last<T: PartialEq>(items: Vec<T>) -> Vec<T> {
+            let mut unique = dedup_first(items.into_iter().rev().collect());
+            unique.reverse();
+            unique
+        }
+
+        assert_eq!(self.link_kind, other.link_kind, "Cannot merge artifacts with different link kinds");
+        Artifacts {
+            include_dirs: dedup_first([self.include_dirs, other.include_dirs].concat()),
+            lib_dirs: dedup_first([self.lib_dirs, other.lib_dirs].concat()),
+            libs: dedup_last([self.libs, other.libs].concat()),
+            link_kind: self.link_kind,
+            runtime_libs: dedup_first([self.runtime_libs, other.runtime_libs].concat()),
+            system_libs: dedup_last([self.system_libs, other.system_libs].concat()),
+            static_system_libs: dedup_last([self.static_system_libs, other.static_system_libs].concat()),
+            system_lib_dirs: dedup_first([self.system_lib_dirs, other.system_lib_dirs].concat()),
+            docs_dir: self.docs_dir.or(other.docs_dir),
+            link_group: self.link_group || other.link_group,
+            whole_archive: self.whole_archive || other.whole_archive,
+            version: self.version.or(other.version),
+            prefix: self.prefix,
+            abi_breaking_checks: self.abi_breaking_checks.or(other.abi_breaking_checks),
+            shared_lib: self.shared_lib.or(other.shared_lib),
+            link_shared_lib: self.link_shared_lib,
+            binaries: dedup_first([self.binaries, other.binaries].concat()),
+            clang_resource_dir: self.clang_resource_dir.or(other.clang_resource_dir),
+            lib_sizes: dedup_last([self.lib_sizes, other.lib_sizes].concat()),
+            cmake_cache: self.cmake_cache.or(other.cmake_cache),
+        }
+    }
+
+    /// Print the cargo metadata.
+    /// When there are multiple include or lib directories, `cargo:include` and `cargo:lib`
+    /// are joined like the `PATH` environment variable.
+    /// `cargo:abi_breaking_checks` is `1` or `0`, so that dependents can compile their C++ code to match.
+    /// The `llvm_version_major` and `llvm_component_<name>` cfgs are set for the package printing them,
+    /// see [Artifacts::components].
+    pub fn print_cargo_metadata(&self) {
+        for line in self.cargo_metadata() {
+            println!("{}", line);
+        }
+    }
+
+    /// Get the lines printed by [Artifacts::print_cargo_metadata].
+    fn cargo_metadata(&self) -> Vec<String> {
+        let mut lines = vec![];
+        for dir in self.lib_dirs.iter() {
+            lines.push(format!("cargo:rustc-link-search=native={}", dir.display()));
+        }
+        for dir in self.system_lib_dirs.iter() {
+            lines.push(format!("cargo:rustc-link-search=native={}", dir.display()));
+        }
+        // rustc links the whole archives with `--whole-archive`, `-force_load` or `/WHOLEARCHIVE`.
+        let kind = match (self.link_kind, self.whole_archive) {
+            (LinkKind::Static, false) => "static",
+            (LinkKind::Static, true) => "static:+whole-archive",
+            (LinkKind::Dylib, _) => "dylib",
+        };
+        // libLLVM already links the system libraries it depends on.
+        match (&self.shared_lib, self.link_shared_lib) {
+            (Some(shared_lib), true) => {
+                lines.push(format!("cargo:rustc-link-lib=dylib={}", shared_lib));
+            }
+            _ => {
+                for lib in self.libs.iter() {
+                    lines.push(format!("cargo:rustc-link-lib={}={}", kind, lib));
+                }
+                for lib in self.system_libs.iter() {
+                    lines.push(format!("cargo:rustc-link-lib=dylib={}", lib));
+                }
+                for lib in self.static_system_libs.iter() {
+                    lines.push(format!("cargo:rustc-link-lib=static={}", lib));
+                }
+            }
+        }
+        // Circular dependencies don't matter once every member is linked.
+        if self.link_group && !self.whole_archive && !self.link_shared_lib {
+            lines.push("cargo:rustc-link-arg=-Wl,--start-group".to_string());
+            for lib in self.libs.iter() {
+                lines.push(format!("cargo:rustc-link-arg=-l{}", lib));
+            }
+            lines.push("cargo:rustc-link-arg=-Wl,--end-group".to_string());
+        }
+        let include = env::join_paths(&self.include_dirs).expect("Invalid include directory");
+        let lib = env::join_paths(&self.lib_dirs).expect("Invalid lib directory");
+        lines.push(format!("cargo:include={}", include.to_string_lossy()));
+        lines.push(format!("cargo:lib={}", lib.to_string_lossy()));
+        if let Some(checks) = self.abi_breaking_checks {
+            lines.push(format!("cargo:abi_breaking_checks={}", if checks { "1" } else { "0" }));
+        }
+        if let Some(dir) = &self.clang_resource_dir {
+            lines.push(format!("cargo:clang_resource_dir={}", dir.display()));
+        }
+
+        lines.push("cargo:rustc-check-cfg=cfg(llvm_version_major, values(any()))".to_string());
+        if let Some(major) = self.version.as_deref().and_then(|v| v.split('.').next()) {
+            lines.push(format!("cargo:rustc-cfg=llvm_version_major=\"{}\"", major));
+        }
+        for component in self.components() {
+            lines.push(format!("cargo:rustc-check-cfg=cfg(llvm_component_{})", component));
+            lines.push(format!("cargo:rustc-cfg=llvm_component_{}", component));
+        }
+        lines
+    }
+}
+
+/// The defines that cmake stores differently than they are passed, e.g. compilers are stored
+/// with their full path, so they are not compared by [CacheMismatchPolicy].
+/// cmake starts from scratch by itself when the compilers change.
+const NORMALIZED_DEFINES: &[&str] = &["CMAKE_C_COMPILER", "CMAKE_CXX_COMPILER"];
+
+/// The LLVM runtimes that are built with `LLVM_ENABLE_RUNTIMES` instead of `LLVM_ENABLE_PROJECTS`.
+const RUNTIMES: &[&str] = &["compiler-rt", "libunwind", "libcxx", "libcxxabi"];
+
+/// The file written in the output directory after a successful build, it contains [Build::config_hash].
+const STAMP_FILE: &str = ".llvm-src-stamp";
+
+/// The file present in the output directory while a build is running.
+const IN_PROGRESS_FILE: &str = ".llvm-src-in-progress";
+
+/// The file written in the output directory after a successful build, it contains [Build::defines].
+const DEFINES_FILE: &str = "defines.json";
+
+/// The number of lines printed from the end of each cmake log when the build fails.
+const LOG_TAIL_LINES: usize = 40;
+
+/// Print the end of the cmake logs in `build_dir` as cargo warnings, so that a failed build
+/// can be diagnosed from the output of cargo.
+fn print_log_tails(build_dir: &Path) {
+    // CMake 3.26 replaced CMakeError.log and CMakeOutput.log with CMakeConfigureLog.yaml.
+    for log in ["CMakeConfigureLog.yaml", "CMakeError.log", "CMakeOutput.log"] {
+        let path = build_dir.join("CMakeFiles").join(log);
+        let Ok(contents) = std::fs::read_to_string(&path) else {
+            continue;
+        };
+        let lines = contents.lines().collect::<Vec<_>>();
+        println!("cargo:warning=Last lines of {}:", path.display());
+        for line in &lines[lines.len().saturating_sub(LOG_TAIL_LINES)..] {
+            println!("cargo:warning={}", line);
+        }
+    }
+}
+
+/// Serialize the defines as a JSON object, with the keys sorted.
+fn defines_json(defines: &BTreeMap<String, String>) -> String {
+    fn quote(value: &str) -> String {
+        let mut quoted = String::from("\"");
+        for c in value.chars() {
+            match c {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                '\n' => quoted.push_str("\\n"),
+                c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+                c => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
+
+    let entries = defines
+        .iter()
+        .map(|(key, value)| format!("  {}: {}", quote(key), quote(value)))
+        .collect::<Vec<_>>();
+    format!("{{\n{}\n}}\n", entries.join(",\n"))
+}
+
+/// Get the `llvm-build` directory in `out_dir`, unless `out_dir` is already one.
+fn llvm_build_dir(out_dir: &Path) -> PathBuf {
+    if out_dir.file_name().is_some_and(|name| name == "llvm-build") {
+        out_dir.to_path_buf()
+    } else {
+        out_dir.join("llvm-build")
+    }
+}
+
+/// Resolve a relative path against the current directory, cmake runs in the build tree.
+fn absolute_path(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Resolve a program path like [absolute_path], but keep plain names to be looked up in `PATH`.
+fn program_path(path: &Path) -> PathBuf {
+    if path.components().count() > 1 {
+        absolute_path(path)
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Get the absolute path of the bundled LLVM source code.
+fn source_dir() -> PathBuf {
+    let source_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("llvm-15.x/llvm");
+    std::path::absolute(source_dir).expect("Failed to resolve the LLVM source directory")
+}
+
+/// A 64-bit FNV-1a hasher.
+/// Unlike [std::hash::DefaultHasher], its output is stable across Rust versions and machines.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    /// Write a string followed by a separator, so that consecutive strings can't collide.
+    fn write_str(&mut self, value: &str) {
+        self.write(value.as_bytes());
+        self.write(&[0]);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hash the LLVM source tree used by the build.
+/// Reading the whole tree is slow, so the revision from [source_revision] is hashed instead
+/// when there is one, and the result is cached for each source directory.
+fn source_hash(source_dir: &Path, projects: &[String], runtimes: &[String]) -> u64 {
+    static CACHE: Mutex<BTreeMap<(PathBuf, Vec<String>), u64>> = Mutex::new(BTreeMap::new());
+
+    let root = source_dir.parent().unwrap_or(source_dir);
+    let key = (
+        source_dir.to_path_buf(),
+        projects.iter().chain(runtimes).cloned().collect::<Vec<_>>(),
+    );
+    if let Some(hash) = CACHE.lock().unwrap().get(&key) {
+        return *hash;
+    }
+    let hash = match source_revision(root) {
+        Some(revision) => {
+            let mut hasher = Fnv1a::new();
+            hasher.write_str(&revision);
+            hasher.finish()
+        }
+        None => source_files_hash(source_dir, projects, runtimes),
+    };
+    CACHE.lock().unwrap().insert(key, hash);
+    hash
+}
+
+/// Get a revision that identifies the contents of the LLVM source tree in `root`:
+/// the version control info of the packaged crate, which can't be modified,
+/// or the commit of a git checkout without local changes.
+fn source_revision(root: &Path) -> Option<String> {
+    let vcs_info = Path::new(env!("CARGO_MANIFEST_DIR")).join(".cargo_vcs_info.json");
+    if let Ok(info) = std::fs::read_to_string(vcs_info) {
+        if !info.contains("\"dirty\": true") {
+            return Some(format!("{} {}", env!("CARGO_PKG_VERSION"), info));
+        }
+    }
+
+    // Without its own `.git`, git would look at the enclosing repository.
+    if !root.join(".git").exists() {
+        return None;
+    }
+    let git = |args: &[&str]| {
+        let output = Command::new("git").arg("-C").arg(root).args(args).output().ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        String::from_utf8(output.stdout).ok()
+    };
+    if !git(&["status", "--porcelain"])?.trim().is_empty() {
+        return None;
+    }
+    Some(git(&["rev-parse", "HEAD"])?.trim().to_string())
+}
+
+/// Hash the contents of the LLVM source tree used by the build: the llvm directory,
+/// the shared cmake modules and the enabled projects and runtimes.
+/// Tests are not built, so their directories are skipped.
+fn source_files_hash(source_dir: &Path, projects: &[String], runtimes: &[String]) -> u64 {
+    let root = source_dir.parent().unwrap_or(source_dir);
+    let mut dirs = vec![source_dir.to_path_buf(), root.join("cmake")];
+    dirs.extend(projects.iter().chain(runtimes).map(|name| root.join(name)));
+
+    let mut files = vec![];
+    for dir in dirs.iter() {
+        walk_files(dir, &mut files);
+    }
+    files.retain(|path| {
+        !path
+            .strip_prefix(root)
+            .unwrap_or(path)
+            .components()
+            .any(|c| matches!(c.as_os_str().to_str(), Some("test" | "unittests" | ".git")))
+    });
+    files.sort();
+    files.dedup();
+
+    let mut hasher = Fnv1a::new();
+    for path in files {
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        hasher.write_str(&relative.to_string_lossy().replace('\\', "/"));
+        if let Ok(contents) = std::fs::read(&path) {
+            hasher.write(&contents);
+        }
+    }
+    hasher.finish()
+}
+
+/// Strip the extension of a shared object file name, including its version, e.g. `LLVMCore.so.15`.
+fn shared_lib_stem(name: &str) -> Option<&str> {
+    match name.find(".so") {
+        Some(index) if name[index + 3..].is_empty() || name[index + 3..].starts_with('.') => Some(&name[..index]),
+        _ => None,
+    }
+}
+
+impl CargoInstructions {
+    /// Get the directories for `cargo:rustc-link-search=native`.
+    pub fn link_search(&self) -> &[PathBuf] {
+        &self.link_search
+    }
+
+    /// Get the libraries for `cargo:rustc-link-lib`, in order.
+    pub fn link_libs(&self) -> &[PlannedLib] {
+        &self.link_libs
+    }
+
+    /// Get the include directories for `cargo:include`.
+    pub fn include_dirs(&self) -> &[PathBuf] {
+        &self.include_dirs
+    }
+
+    /// Get the lib directories for `cargo:lib`.
+    pub fn lib_dirs(&self) -> &[PathBuf] {
+        &self.lib_dirs
+    }
+}
+
+/// A library file found by [discover_libs].
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Lib {
+    /// The name passed to `rustc-link-lib`.
+    name: String,
+    path: PathBuf,
+}
+
+/// Check if the target uses the MSVC library layout, MinGW targets use the GNU one.
+fn is_msvc_layout(target: &str) -> bool {
+    target.contains("windows") && !target.contains("gnu")
+}
+
+/// Get the prefix of library file names for the target.
+fn lib_prefix(target: &str) -> &'static str {
+    if is_msvc_layout(target) {
+        ""
+    } else {
+        "lib"
+    }
+}
+
+/// Strip the extension of a library file name for the target, if it matches the kind.
+/// MSVC targets use `.lib` and `.dll`, MinGW targets `.a` and `.dll.a` import libraries,
+/// Apple targets `.a` and `.dylib`, the others `.a` and `.so`, possibly versioned.
+fn strip_lib_extension<'a>(name: &'a str, target: &str, kind: LinkKind) -> Option<&'a str> {
+    let mingw = target.contains("windows") && !is_msvc_layout(target);
+    match (is_msvc_layout(target), kind) {
+        (true, LinkKind::Static) => name.strip_suffix(".lib"),
+        (true, LinkKind::Dylib) => name.strip_suffix(".dll"),
+        (false, LinkKind::Static) if mingw => {
+            name.strip_suffix(".a").filter(|stem| !stem.ends_with(".dll"))
+        }
+        (false, LinkKind::Dylib) if mingw => name.strip_suffix(".dll.a"),
+        (false, LinkKind::Static) => name.strip_suffix(".a"),
+        (false, LinkKind::Dylib) if target.contains("apple") => name.strip_suffix(".dylib"),
+        (false, LinkKind::Dylib) => shared_lib_stem(name),
+    }
+}
+
+/// Get the total memory of the build machine in bytes, if it can be determined.
+fn total_memory() -> Option<u64> {
+    if cfg!(target_os = "linux") {
+        let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+        let line = meminfo.lines().find(|line| line.starts_with("MemTotal:"))?;
+        let kib = line.trim_start_matches("MemTotal:").trim().trim_end_matches("kB").trim();
+        kib.parse::<u64>().ok().map(|kib| kib * 1024)
+    } else if cfg!(target_os = "macos") {
+        let output = Command::new("sysctl").arg("-n").arg("hw.memsize").output().ok()?;
+        String::from_utf8(output.stdout).ok()?.trim().parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Read the entries of a `CMakeCache.txt` file, without their types.
+fn read_cmake_cache(path: &Path) -> io::Result<BTreeMap<String, String>> {
+    let mut entries = BTreeMap::new();
+    for line in std::fs::read_to_string(path)?.lines() {
+        if line.starts_with("//") || line.starts_with('#') {
+            continue;
+        }
+        // Entries are `KEY:TYPE=VALUE`.
+        if let Some((key_type, value)) = line.split_once('=') {
+            let key = key_type.split_once(':').map_or(key_type, |(key, _)| key);
+            entries.insert(key.to_string(), value.to_string());
+        }
+    }
+    Ok(entries)
+}
+
+/// Check that the member headers of an `ar` archive are complete, for both regular and thin archives.
+fn is_valid_archive(path: &Path) -> io::Result<bool> {
+    let len = std::fs::metadata(path)?.len();
+    let mut file = std::fs::File::open(path)?;
+    let mut magic = [0; 8];
+    if file.read_exact(&mut magic).is_err() {
+        return Ok(false);
+    }
+    let thin = match &magic {
+        b"!<arch>\n" => false,
+        b"!<thin>\n" => true,
+        _ => return Ok(false),
+    };
+
+    let mut offset = 8;
+    while offset < len {
+        let mut header = [0; 60];
+        if file.read_exact(&mut header).is_err() || &header[58..] != b"`\n" {
+            return Ok(false);
+        }
+        let Some(size) = std::str::from_utf8(&header[48..58])
+            .ok()
+            .and_then(|size| size.trim().parse::<u64>().ok())
+        else {
+            return Ok(false);
+        };
+        // The members of thin archives are stored outside, except for the symbol and name tables.
+        let name = &header[..16];
+        let stored = !thin
+            || name.starts_with(b"/ ")
+            || name.starts_with(b"// ")
+            || name.starts_with(b"/SYM64/");
+        let data = if stored { size + size % 2 } else { 0 };
+        offset += 60 + data;
+        if offset > len + size % 2 {
+            return Ok(false);
+        }
+        file.seek(io::SeekFrom::Start(offset))?;
+    }
+    Ok(true)
+}
+
+/// Find the MLIR libraries in `lib_dir`, ordered so that each library comes before its dependencies.
+/// The dependencies are read from the cmake exports of MLIR, `lib/cmake/mlir/MLIRTargets.cmake`,
+/// without them the libraries are sorted by name.
+fn mlir_libs(lib_dir: &Path, target: &str, kind: LinkKind) -> Vec<String> {
+    fn visit(
+        name: &str,
+        deps: &BTreeMap<String, Vec<String>>,
+        names: &[String],
+        visited: &mut BTreeSet<String>,
+        order: &mut Vec<String>,
+    ) {
+        if !visited.insert(name.to_string()) {
+            return;
+        }
+        for dep in deps.get(name).into_iter().flatten() {
+            if names.contains(dep) {
+                visit(dep, deps, names, visited, order);
+            }
+        }
+        order.push(name.to_string());
+    }
+
+    let names = discover_libs(lib_dir, target, kind, None, None)
+        .into_iter()
+        .map(|lib| lib.name)
+        .filter(|name| name.starts_with("MLIR"))
+        .collect::<Vec<_>>();
+    let exports = lib_dir.join("cmake").join("mlir").join("MLIRTargets.cmake");
+    let deps = cmake_link_deps(&std::fs::read_to_string(exports).unwrap_or_default());
+
+    // Dependencies are visited first, so the order is reversed.
+    let mut visited = BTreeSet::new();
+    let mut order = vec![];
+    for name in names.iter() {
+        visit(name, &deps, &names, &mut visited, &mut order);
+    }
+    order.reverse();
+    order
+}
+
+/// Get the `INTERFACE_LINK_LIBRARIES` of each target in a cmake export file.
+fn cmake_link_deps(exports: &str) -> BTreeMap<String, Vec<String>> {
+    let mut deps = BTreeMap::new();
+    let mut current = None;
+    for line in exports.lines().map(str::trim) {
+        if let Some(rest) = line.strip_prefix("set_target_properties(") {
+            current = rest.split_whitespace().next().map(str::to_string);
+            continue;
+        }
+        if let (Some(name), Some(value)) = (&current, line.strip_prefix("INTERFACE_LINK_LIBRARIES ")) {
+            let libs = value
+                .trim_matches('"')
+                .split(';')
+                .map(|lib| lib.trim_start_matches("$<LINK_ONLY:").trim_end_matches('>').to_string())
+                .collect();
+            deps.insert(name.clone(), libs);
+        }
+    }
+    deps
+}
+
+/// Get the value of `LLVM_VERSION_STRING` from the contents of `llvm/Config/llvm-config.h`.
+fn llvm_version(header: &str) -> Option<String> {
+    header.lines().find_map(|line| {
+        let value = line.strip_prefix("#define LLVM_VERSION_STRING")?;
+        Some(value.trim().trim_matches('"').to_string())
+    })
+}
+
+/// Get the link name of a library file for the target,
+/// or `None` if it's not a library of the given kind.
+/// `prefix` and `suffix` replace the standard prefix and extensions, see [Build::strip_prefix].
+fn lib_name<'a>(
+    file_name: &'a str,
+    target: &str,
+    kind: LinkKind,
+    prefix: Option<&str>,
+    suffix: Option<&str>,
+) -> Option<&'a str> {
+    let stem = file_name.strip_prefix(prefix.unwrap_or(lib_prefix(target)))?;
+    let name = match suffix {
+        Some(suffix) => stem.strip_suffix(suffix)?,
+        None => strip_lib_extension(stem, target, kind)?,
+    };
+    // Built only for LLVM's own unit tests.
+    if name.is_empty() || name.starts_with("gtest") || name.starts_with("gmock") {
+        return None;
+    }
+    Some(name)
+}
+
+/// Find the libraries of the given kind in `dir`, sorted by name.
+/// Files that are not libraries are skipped, e.g. cmake exports, and a library with more
+/// than one file (e.g. versioned shared objects and their symlinks) is reported once.
+/// `prefix` and `suffix` are passed to [lib_name].
+fn discover_libs(
+    dir: &Path,
+    target: &str,
+    kind: LinkKind,
+    prefix: Option<&str>,
+    suffix: Option<&str>,
+) -> Vec<Lib> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut libs = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .filter_map(|path| {
+            let file_name = path.file_name()?.to_str()?;
+            let name = lib_name(file_name, target, kind, prefix, suffix)?.to_string();
+            Some(Lib { name, path })
+        })
+        .collect::<Vec<_>>();
+    libs.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.path.cmp(&b.path)));
+    libs.dedup_by(|a, b| a.name == b.name);
+    libs
+}
+
+/// System libraries typically required by LLVM libraries, as `(llvm_lib, system_lib, target)`.
+/// An entry applies when the target triple contains `target`.
+const SYSTEM_LIB_DEPS: &[(&str, &str, &str)] = &[
+    ("LLVMSupport", "pthread", "linux"),
+    ("LLVMSupport", "dl", "linux"),
+    ("LLVMSupport", "rt", "linux"),
+    ("LLVMSupport", "m", "linux"),
+    ("LLVMSupport", "m", "apple"),
+    ("LLVMSupport", "psapi", "windows"),
+    ("LLVMSupport", "shell32", "windows"),
+    ("LLVMSupport", "ole32", "windows"),
+    ("LLVMSupport", "uuid", "windows"),
+    ("LLVMSupport", "advapi32", "windows"),
+];
+
+/// Check the discovered libraries against [SYSTEM_LIB_DEPS].
+/// Returns a warning for each system library that is likely needed but will not be linked,
+/// and one for the system libraries that were reported more than once.
+fn system_lib_warnings(
+    target: &str,
+    libs: &[String],
+    system_libs: &[String],
+    duplicate_libs: &[String],
+) -> Vec<String> {
+    let mut warnings = vec![];
+    for (llvm_lib, system_lib, target_pattern) in SYSTEM_LIB_DEPS {
+        if target.contains(target_pattern)
+            && libs.iter().any(|l| l == llvm_lib)
+            && !system_libs.iter().any(|l| l == system_lib)
+        {
+            warnings.push(format!(
+                "{} usually requires the system library `{}` on {}, but it will not be linked",
+                llvm_lib, system_lib, target
+            ));
+        }
+    }
+    if !duplicate_libs.is_empty() {
+        warnings.push(format!(
+            "llvm-config reported these system libraries more than once: {}",
+            duplicate_libs.join(", ")
+        ));
+    }
+    warnings
+}
+
+/// Collect all the files under `dir`, recursively.
+/// Missing or unreadable directories are skipped.
+fn walk_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            walk_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// Find the libraries produced by the LLVM runtimes in the lib directory.
+/// compiler-rt installs into `lib/clang/<version>/lib/<os>`, the other runtimes
+/// (libunwind, libc++, libc++abi) install directly into `lib`.
+fn find_runtime_libs(lib_dir: &Path) -> Vec<PathBuf> {
+    let mut files = vec![];
+    walk_files(&lib_dir.join("clang"), &mut files);
+    files.retain(|path| {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("libclang_rt.") || name.starts_with("clang_rt."))
+    });
+
+    if let Ok(entries) = std::fs::read_dir(lib_dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_runtime = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    ["libunwind.", "libc++.", "libc++abi.", "unwind.", "c++.", "c++abi."]
+                        .iter()
+                        .any(|prefix| name.starts_with(prefix))
+                });
+            if is_runtime && path.is_file() {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create an empty directory under `target` for a test, with the given empty files in it.
+    /// The path is relative to the crate root, where the tests run.
+    fn fixture(name: &str, files: &[&str]) -> PathBuf {
+        let dir = Path::new("target").join("llvm-src-tests").join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        for file in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn relative_dirs_give_absolute_paths() {
+        let dir = fixture("relative_dirs", &["lib/libLLVMCore.a", "include/llvm/Config/llvm-config.h"]);
+        assert!(dir.is_relative());
+        let artifacts = Build::new().target("x86_64-unknown-linux-gnu").from_build_dir(&dir);
+        assert!(artifacts.include().is_absolute());
+        assert!(artifacts.lib().is_absolute());
+        assert_eq!(artifacts.libs(), ["LLVMCore"]);
+
+        let instructions = Build::new().out_dir(&dir).cargo_instructions().unwrap();
+        assert!(instructions.lib_dirs().iter().all(|dir| dir.is_absolute()));
+        assert!(instructions.include_dirs().iter().all(|dir| dir.is_absolute()));
+
+        let include_dir = dir.join("include");
+        let artifacts = Build::new()
+            .target("x86_64-unknown-linux-gnu")
+            .include_dir(&include_dir)
+            .from_build_dir(&dir);
+        assert_eq!(artifacts.include(), std::path::absolute(&include_dir).unwrap());
+        let mut build = Build::new();
+        let instructions = build.out_dir(&dir).include_dir(&include_dir).cargo_instructions();
+        assert!(instructions.unwrap().include_dirs()[0].is_absolute());
+    }
+
+    #[test]
+    fn discover_libs_by_target() {
+        let dir = fixture(
+            "discover_libs_by_target",
+            &[
+                "libLLVMCore.a",
+                "libLLVMAnalysis.a",
+                "LLVMSupport.lib",
+                "libLLVM-15.so.15.0.7",
+                "libLLVM.dylib",
+                "LLVMExports.cmake",
+                "libgtest.a",
+            ],
+        );
+        let names = |target: &str, kind: LinkKind| {
+            discover_libs(&dir, target, kind, None, None)
+                .into_iter()
+                .map(|lib| lib.name)
+                .collect::<Vec<_>>()
+        };
+
+        let linux = "x86_64-unknown-linux-gnu";
+        assert_eq!(names(linux, LinkKind::Static), ["LLVMAnalysis", "LLVMCore"]);
+        assert_eq!(names(linux, LinkKind::Dylib), ["LLVM-15"]);
+        let msvc = "x86_64-pc-windows-msvc";
+        assert_eq!(names(msvc, LinkKind::Static), ["LLVMSupport"]);
+        assert!(names(msvc, LinkKind::Dylib).is_empty());
+        let apple = "aarch64-apple-darwin";
+        assert_eq!(names(apple, LinkKind::Static), ["LLVMAnalysis", "LLVMCore"]);
+        assert_eq!(names(apple, LinkKind::Dylib), ["LLVM"]);
+
+        let dylibs = discover_libs(&dir, linux, LinkKind::Dylib, None, None);
+        assert_eq!(dylibs[0].path, dir.join("libLLVM-15.so.15.0.7"));
+    }
+
+    #[test]
+    fn lib_name_skips_non_libraries() {
+        let name = |file_name, target, kind| lib_name(file_name, target, kind, None, None);
+        let linux = "x86_64-unknown-linux-gnu";
+        assert_eq!(name("libLLVMCore.a", linux, LinkKind::Static), Some("LLVMCore"));
+        assert_eq!(name("LLVMExports.cmake", linux, LinkKind::Static), None);
+        assert_eq!(name("libgtest.a", linux, LinkKind::Static), None);
+        assert_eq!(name("libLLVMCore.a", linux, LinkKind::Dylib), None);
+        assert_eq!(name("libLLVMCore.a", "x86_64-pc-windows-msvc", LinkKind::Static), None);
+        assert_eq!(
+            lib_name("libLLVMCore-15.a", linux, LinkKind::Static, None, Some("-15.a")),
+            Some("LLVMCore")
+        );
+    }
+
+    #[test]
+    fn lib_name_normalizes_msvc_names() {
+        let target = "x86_64-pc-windows-msvc";
+        let name = |file_name, kind| lib_name(file_name, target, kind, None, None);
+        assert_eq!(name("LLVMCore.lib", LinkKind::Static), Some("LLVMCore"));
+        assert_eq!(name("LLVM-C.lib", LinkKind::Static), Some("LLVM-C"));
+        assert_eq!(name("LLVMCore.dll", LinkKind::Dylib), Some("LLVMCore"));
+        assert_eq!(name("LLVMCore.dll", LinkKind::Static), None);
+        assert_eq!(name("LLVMCore.lib", LinkKind::Dylib), None);
+    }
+
+    #[test]
+    fn lib_name_normalizes_linux_names() {
+        let target = "x86_64-unknown-linux-gnu";
+        let name = |file_name, kind| lib_name(file_name, target, kind, None, None);
+        assert_eq!(name("libLLVMCore.a", LinkKind::Static), Some("LLVMCore"));
+        assert_eq!(name("libLLVMCore.so", LinkKind::Dylib), Some("LLVMCore"));
+        assert_eq!(name("libLLVMCore.so.15", LinkKind::Dylib), Some("LLVMCore"));
+        assert_eq!(name("libLLVM-15.so.15.0.7", LinkKind::Dylib), Some("LLVM-15"));
+        assert_eq!(name("libLLVMCore.source", LinkKind::Dylib), None);
+        assert_eq!(name("libLLVMCore.dylib", LinkKind::Dylib), None);
+        assert_eq!(name("LLVMCore.a", LinkKind::Static), None);
+    }
+
+    #[test]
+    fn relative_rpath_keeps_lib_dir() {
+        let rpath = |target| {
+            let defines = Build::new().target(target).relative_rpath(true).defines();
+            defines["CMAKE_INSTALL_RPATH"].clone()
+        };
+        assert_eq!(rpath("x86_64-unknown-linux-gnu"), "$ORIGIN;$ORIGIN/../lib");
+        assert_eq!(rpath("aarch64-apple-darwin"), "@loader_path;@loader_path/../lib");
+    }
+
+    #[test]
+    fn whole_archive_uses_link_modifier() {
+        let dir = fixture("whole_archive", &["lib/libLLVMCore.a", "lib/libLLVMSupport.a"]);
+        let lines = Build::new()
+            .target("x86_64-unknown-linux-gnu")
+            .whole_archive(true)
+            .from_build_dir(&dir)
+            .cargo_metadata();
+        assert!(lines.contains(&"cargo:rustc-link-lib=static:+whole-archive=LLVMCore".to_string()));
+        assert!(lines.iter().all(|line| !line.starts_with("cargo:rustc-link-arg")));
+
+        let artifacts = Build::new().target("x86_64-unknown-linux-gnu").from_build_dir(&dir);
+        let lines = artifacts.cargo_metadata();
+        assert!(lines.contains(&"cargo:rustc-link-lib=static=LLVMCore".to_string()));
+        assert!(lines.contains(&"cargo:rustc-link-arg=-Wl,--start-group".to_string()));
+    }
+
+    #[test]
+    fn cargo_instructions_link_shared_lib() {
+        let dir = fixture("cargo_instructions_link_shared_lib", &[]);
+        let instructions = Build::new()
+            .target("x86_64-unknown-linux-gnu")
+            .out_dir(&dir)
+            .required_libs(vec!["core"])
+            .with_curl(true)
+            .build_dylib(true)
+            .link_static(false)
+            .cargo_instructions()
+            .unwrap();
+        let shared_lib = PlannedLib::Known {
+            kind: LinkKind::Dylib,
+            name: "LLVM".to_string(),
+        };
+        assert_eq!(instructions.link_libs(), [shared_lib]);
+    }
+
+    #[test]
+    fn out_dir_appends_llvm_build_once() {
+        let expected = Path::new("x").join("llvm-build");
+        let mut build = Build::new();
+        assert_eq!(build.out_dir(Path::new("x")).out_dir.as_deref(), Some(expected.as_path()));
+        assert_eq!(build.out_dir(&expected).out_dir.as_deref(), Some(expected.as_path()));
+    }
+
+    #[test]
+    fn build_and_install_rejects_thin_archives() {
+        let result = Build::new().thin_archives(true).build_and_install();
+        assert!(matches!(result, Err(BuildError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn discover_libs_mingw() {
+        let dir = fixture("discover_libs_mingw", &["libLLVMCore.a", "libLLVM.dll.a"]);
+        let names = |kind| {
+            discover_libs(&dir, "x86_64-pc-windows-gnu", kind, None, None)
+                .into_iter()
+                .map(|lib| lib.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(LinkKind::Static), ["LLVMCore"]);
+        assert_eq!(names(LinkKind::Dylib), ["LLVM"]);
+    }
+
+    #[test]
+    fn reproducible_maps_the_build_dir() {
+        let mut build = Build::new();
+        build
+            .host("x86_64-unknown-linux-gnu")
+            .target("x86_64-unknown-linux-gnu")
+            .profile("Release")
+            .out_dir(Path::new("/machine-a/out"))
+            .reproducible(true);
+        let build_dir = std::path::absolute("/machine-a/out/llvm-build/build").unwrap();
+        let prefix_map = format!("-ffile-prefix-map={}=llvm-build", build_dir.display());
+        assert!(build.compiler_flags().contains(&prefix_map));
+
+        let hash = build.config_hash();
+        assert_eq!(build.out_dir(Path::new("/machine-b/out")).config_hash(), hash);
+        assert_ne!(build.reproducible(false).config_hash(), hash);
+    }
+
+    #[test]
+    fn build_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Build>();
+    }
+
+    #[test]
+    fn build_env_includes_option_vars() {
+        let target = "x86_64-unknown-linux-gnu";
+        let vars = Build::new()
+            .cache_dir_for_launcher(Path::new("cache"))
+            .reproducible(true)
+            .build_env(target, target);
+        for key in ["CCACHE_DIR", "SCCACHE_DIR", "ZERO_AR_DATE"] {
+            assert!(vars.iter().any(|(k, _)| k == key), "{} is missing", key);
+        }
+    }
+
+    #[test]
+    fn cross_builds_skip_llvm_config() {
+        // The llvm-config of the target can't run on the host.
+        let files = ["bin/llvm-config", "lib/libLLVMCore.a"];
+        let dir = fixture("cross_builds_skip_llvm_config", &files);
+        let artifacts = Build::new()
+            .host("x86_64-unknown-linux-gnu")
+            .target("aarch64-unknown-linux-gnu")
+            .from_build_dir(&dir);
+        assert_eq!(artifacts.libs(), ["LLVMCore"]);
+    }
+
+    #[test]
+    fn cache_mismatch_skips_compilers() {
+        let dir = fixture("cache_mismatch_skips_compilers", &["llvm-build/build/build.ninja"]);
+        let cache = "CMAKE_C_COMPILER:STRING=/usr/bin/clang\nLLVM_ENABLE_PIC:BOOL=OFF\n";
+        std::fs::write(dir.join("llvm-build/build/CMakeCache.txt"), cache).unwrap();
+        let target = "x86_64-unknown-linux-gnu";
+        let result = Build::new()
+            .host(target)
+            .target(target)
+            .profile("Release")
+            .out_dir(&dir)
+            .c_compiler(Path::new("clang"))
+            .on_cache_mismatch(CacheMismatchPolicy::Error)
+            .try_build();
+        match result {
+            Err(BuildError::CacheMismatch { key, .. }) => assert_eq!(key, "LLVM_ENABLE_PIC"),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn relative_paths_are_absolute_in_defines() {
+        let defines = Build::new()
+            .include_path(Path::new("deps/include"))
+            .library_path(Path::new("deps/lib"))
+            .with_z3(true)
+            .z3_dir(Path::new("deps/z3"))
+            .c_compiler(Path::new("clang"))
+            .cxx_compiler(Path::new("toolchain/bin/clang++"))
+            .host_c_compiler(Path::new("toolchain/bin/clang"))
+            .defines();
+        let keys = [
+            "CMAKE_INCLUDE_PATH",
+            "CMAKE_LIBRARY_PATH",
+            "LLVM_Z3_INSTALL_DIR",
+            "CMAKE_CXX_COMPILER",
+        ];
+        for key in keys {
+            assert!(Path::new(&defines[key]).is_absolute(), "{} is relative", key);
         }
-        println!("cargo:include={}", self.include_dir.display());
-        println!("cargo:lib={}", self.lib_dir.display());
+        assert_eq!(defines["CMAKE_C_COMPILER"], "clang");
+        let native_compiler = std::path::absolute("toolchain/bin/clang").unwrap();
+        let native_flag = format!("-DCMAKE_C_COMPILER={}", native_compiler.display());
+        assert_eq!(defines["CROSS_TOOLCHAIN_FLAGS_NATIVE"], native_flag);
     }
 }